@@ -1,6 +1,12 @@
-//! Buildscript which will save a `rust_g.dm` with the DLL's public API.
+//! Buildscript which will save a `rust_g.dm` with the DLL's public API, and
+//! generate the build info returned by `get_build_info`.
 
-use std::{fs::File, io::Write};
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 macro_rules! feature_dm_file {
     ($name:expr) => {
@@ -39,4 +45,108 @@ fn main() {
             }
         }
     }
+
+    write_build_info();
+    rerun_if_changed();
+}
+
+fn feature_enabled(feature: &str) -> bool {
+    std::env::var_os(format!(
+        "CARGO_FEATURE_{}",
+        feature.to_uppercase().replace('-', "_")
+    ))
+    .is_some()
+}
+
+fn json_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn json_list(values: &[String]) -> String {
+    let values: Vec<String> = values.iter().map(|v| json_string(v)).collect();
+    format!("[{}]", values.join(","))
+}
+
+/// The features declared in `Cargo.toml` which this build has enabled.
+fn enabled_features() -> Vec<String> {
+    let manifest = std::fs::read_to_string("Cargo.toml").unwrap();
+    let mut features = Vec::new();
+    let mut in_default = false;
+    for line in manifest
+        .lines()
+        .skip_while(|line| line.trim() != "[features]")
+        .skip(1)
+        .take_while(|line| !line.starts_with('['))
+        .map(str::trim)
+    {
+        if let Some((name, _)) = line.split_once(" = ") {
+            in_default = name == "default";
+            if !in_default {
+                features.push(name.to_owned());
+            }
+        } else if in_default {
+            // Default features which are just optional dependencies (like
+            // `noise`) aren't declared on their own line.
+            features.extend(
+                line.trim_end_matches(',')
+                    .strip_prefix('"')
+                    .and_then(|name| name.strip_suffix('"'))
+                    .map(str::to_owned),
+            );
+        }
+    }
+    features.sort();
+    features.dedup();
+    features.retain(|name| feature_enabled(name));
+    features
+}
+
+/// Output of a git command run in the crate's directory, if it worked.
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Naming any file turns off Cargo's default of rerunning on every change in
+/// the package, so this lists everything the outputs are built from: the DM
+/// sources, the features, and the checked out commit.
+fn rerun_if_changed() {
+    println!("cargo:rerun-if-changed=dmsrc");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    let git_dir = match git(&["rev-parse", "--git-dir"]) {
+        Some(git_dir) => PathBuf::from(git_dir),
+        None => return,
+    };
+    let head = git_dir.join("HEAD");
+    println!("cargo:rerun-if-changed={}", head.display());
+    // A branch moves without HEAD changing, so watch the ref as well. It may
+    // only be in `packed-refs`, and a missing path would rerun every build.
+    let branch = std::fs::read_to_string(&head)
+        .ok()
+        .and_then(|head| head.trim().strip_prefix("ref: ").map(str::to_owned));
+    if let Some(branch) = branch {
+        for path in [git_dir.join(branch), git_dir.join("packed-refs")] {
+            if path.exists() {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
+    }
+}
+
+fn write_build_info() {
+    let info = format!(
+        "{{\"version\":{},\"features\":{},\"git_commit\":{},\"target\":{},\"profile\":{}}}",
+        json_string(&std::env::var("CARGO_PKG_VERSION").unwrap()),
+        json_list(&enabled_features()),
+        json_string(&git(&["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".to_owned())),
+        json_string(&std::env::var("TARGET").unwrap()),
+        json_string(&std::env::var("PROFILE").unwrap()),
+    );
+
+    let out = Path::new(&std::env::var("OUT_DIR").unwrap()).join("build_info.json");
+    std::fs::write(out, info).unwrap();
 }
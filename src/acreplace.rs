@@ -1,3 +1,4 @@
+use crate::error::envelope;
 use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
 use serde::Deserialize;
 use std::{cell::RefCell, collections::hash_map::HashMap};
@@ -54,27 +55,55 @@ thread_local! {
 }
 
 byond_fn!(fn setup_acreplace(key, patterns_json, replacements_json) {
-    let patterns: Vec<String> = serde_json::from_str(patterns_json).ok()?;
-    let replacements: Vec<String> = serde_json::from_str(replacements_json).ok()?;
-    let ac = AhoCorasickBuilder::new().auto_configure(&patterns).build(&patterns);
-    CREPLACE_MAP.with(|cell| {
-        let mut map = cell.borrow_mut();
-        map.insert(key.to_owned(), Replacements { automaton: ac, replacements });
-    });
+    setup(key, None, patterns_json, replacements_json).ok()?;
     Some("")
 });
 
 byond_fn!(fn setup_acreplace_with_options(key, options_json, patterns_json, replacements_json) {
-    let options: AhoCorasickOptions = serde_json::from_str(options_json).ok()?;
-    let patterns: Vec<String> = serde_json::from_str(patterns_json).ok()?;
-    let replacements: Vec<String> = serde_json::from_str(replacements_json).ok()?;
-    let ac = options.auto_configure_and_build(&patterns);
+    setup(key, Some(options_json), patterns_json, replacements_json).ok()?;
+    Some("")
+});
+
+byond_fn!(fn setup_acreplace_v2(key, patterns_json, replacements_json) {
+    Some(envelope(
+        setup(key, None, patterns_json, replacements_json).map(|()| serde_json::Value::Null),
+    ))
+});
+
+byond_fn!(fn setup_acreplace_with_options_v2(key, options_json, patterns_json, replacements_json) {
+    Some(envelope(
+        setup(key, Some(options_json), patterns_json, replacements_json)
+            .map(|()| serde_json::Value::Null),
+    ))
+});
+
+fn setup(
+    key: &str,
+    options_json: Option<&str>,
+    patterns_json: &str,
+    replacements_json: &str,
+) -> crate::error::Result<()> {
+    let options: Option<AhoCorasickOptions> = options_json.map(serde_json::from_str).transpose()?;
+    let patterns: Vec<String> = serde_json::from_str(patterns_json)?;
+    let replacements: Vec<String> = serde_json::from_str(replacements_json)?;
+    let automaton = match options {
+        Some(options) => options.auto_configure_and_build(&patterns),
+        None => AhoCorasickBuilder::new()
+            .auto_configure(&patterns)
+            .build(&patterns),
+    };
     CREPLACE_MAP.with(|cell| {
         let mut map = cell.borrow_mut();
-        map.insert(key.to_owned(), Replacements { automaton: ac, replacements });
+        map.insert(
+            key.to_owned(),
+            Replacements {
+                automaton,
+                replacements,
+            },
+        );
     });
-    Some("")
-});
+    Ok(())
+}
 
 byond_fn!(fn acreplace(key, text) {
     CREPLACE_MAP.with(|cell| -> Option<String> {
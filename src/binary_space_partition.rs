@@ -1,4 +1,4 @@
-use crate::error::Result;
+use crate::error::{envelope, Result};
 use rand::prelude::*;
 use rand::rngs::StdRng;
 use rand::Rng;
@@ -16,6 +16,10 @@ byond_fn!(fn bsp_generate(width, height, hash, map_subsection_min_size, map_subs
     bsp_gen(width, height, hash, map_subsection_min_size, map_subsection_min_room_width, map_subsection_min_room_height).ok()
 });
 
+byond_fn!(fn bsp_generate_v2(width, height, hash, map_subsection_min_size, map_subsection_min_room_width, map_subsection_min_room_height) {
+    Some(envelope(bsp_gen(width, height, hash, map_subsection_min_size, map_subsection_min_room_width, map_subsection_min_room_height)))
+});
+
 fn bsp_gen(
     width_as_str: &str,
     height_as_str: &str,
@@ -1,14 +1,27 @@
 use std::{
+    backtrace::Backtrace,
     borrow::Cow,
-    cell::RefCell,
+    cell::{Cell, RefCell},
     ffi::{CStr, CString},
+    fs::OpenOptions,
+    io::Write,
     os::raw::{c_char, c_int},
+    panic::{self, AssertUnwindSafe},
     slice,
+    sync::{Once, OnceLock},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+/// What a call returns to BYOND instead of its result if it panicked.
+pub const PANIC_RESULT: &str = "RUSTG PANICKED";
+const PANIC_LOG: &str = "rustg_panic.log";
+
 static EMPTY_STRING: c_char = 0;
+static PANIC_HOOK: Once = Once::new();
 thread_local! {
     static RETURN_STRING: RefCell<CString> = RefCell::new(CString::default());
+    static CURRENT_CALL: Cell<Option<&'static str>> = const { Cell::new(None) };
 }
 
 pub unsafe fn parse_args<'a>(argc: c_int, argv: *const *const c_char) -> Vec<Cow<'a, str>> {
@@ -42,20 +55,89 @@ pub fn byond_return(value: Option<Vec<u8>>) -> *const c_char {
     }
 }
 
+// Appends the panic and a backtrace to the crash log before the default hook
+// runs. This covers job threads too, not just calls from BYOND.
+fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let location = CURRENT_CALL
+                .with(Cell::get)
+                .map(str::to_owned)
+                .or_else(|| thread::current().name().map(str::to_owned))
+                .unwrap_or_else(|| "unnamed thread".to_owned());
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            if let Ok(mut file) = OpenOptions::new().append(true).create(true).open(PANIC_LOG) {
+                let _ = writeln!(
+                    file,
+                    "[{}] panic in {}: {}\n{}",
+                    timestamp,
+                    location,
+                    info,
+                    Backtrace::force_capture()
+                );
+            }
+            default_hook(info);
+        }));
+    });
+}
+
+/// Runs the body of an exported function, making sure a panic can't unwind
+/// into BYOND.
+pub fn byond_call<R, F>(name: &'static str, f: F) -> *const c_char
+where
+    R: Into<Vec<u8>>,
+    F: FnOnce() -> Option<R>,
+{
+    install_panic_hook();
+    let outer = CURRENT_CALL.with(|cell| cell.replace(Some(name)));
+    #[cfg(feature = "profile")]
+    let started = std::time::Instant::now();
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    #[cfg(feature = "profile")]
+    crate::profile::record(name, started.elapsed());
+    CURRENT_CALL.with(|cell| cell.set(outer));
+    match result {
+        Ok(value) => byond_return(value.map(Into::into)),
+        Err(_) => byond_return(Some(PANIC_RESULT.into())),
+    }
+}
+
+/// An exported function and its arguments, as listed by `get_build_info`.
+/// `byond_fn!` registers one for every function it exports.
+pub struct Export {
+    pub name: &'static str,
+    pub args: &'static [&'static str],
+}
+
+inventory::collect!(Export);
+
 #[macro_export]
 macro_rules! byond_fn {
     (fn $name:ident() $body:block) => {
+        ::inventory::submit! {
+            $crate::byond::Export { name: stringify!($name), args: &[] }
+        }
+
         #[no_mangle]
         #[allow(clippy::missing_safety_doc)]
         pub unsafe extern "C" fn $name(
             _argc: ::std::os::raw::c_int, _argv: *const *const ::std::os::raw::c_char
         ) -> *const ::std::os::raw::c_char {
-            let closure = || ($body);
-            $crate::byond::byond_return(closure().map(From::from))
+            $crate::byond::byond_call(stringify!($name), || ($body))
         }
     };
 
     (fn $name:ident($($arg:ident),* $(, ...$rest:ident)?) $body:block) => {
+        ::inventory::submit! {
+            $crate::byond::Export {
+                name: stringify!($name),
+                args: &[$(stringify!($arg),)* $(concat!("...", stringify!($rest)))?],
+            }
+        }
+
         #[no_mangle]
         #[allow(clippy::missing_safety_doc)]
         pub unsafe extern "C" fn $name(
@@ -72,8 +154,7 @@ macro_rules! byond_fn {
                 let $rest = __args.get(__argn..).unwrap_or(&[]);
             )?
 
-            let closure = || ($body);
-            $crate::byond::byond_return(closure().map(From::from))
+            $crate::byond::byond_call(stringify!($name), || ($body))
         }
     };
 }
@@ -84,3 +165,62 @@ byond_fn!(
         Some(env!("CARGO_PKG_VERSION"))
     }
 );
+
+// JSON describing what this build can do: enabled features, every exported
+// function with its arguments, and where the build came from.
+byond_fn!(
+    fn get_build_info() {
+        static INFO: OnceLock<String> = OnceLock::new();
+        Some(INFO.get_or_init(build_info).as_str())
+    }
+);
+
+// The build script writes everything but the exports, which are only known
+// once the registered functions are linked in.
+fn build_info() -> String {
+    let info = include_str!(concat!(env!("OUT_DIR"), "/build_info.json"));
+    let mut exports: Vec<String> = inventory::iter::<Export>
+        .into_iter()
+        .map(|export| {
+            let args: Vec<String> = export
+                .args
+                .iter()
+                .map(|arg| format!("\"{}\"", arg))
+                .collect();
+            format!("\"{}\":[{}]", export.name, args.join(","))
+        })
+        .collect();
+    exports.sort();
+    format!(
+        "{},\"exports\":{{{}}}}}",
+        info.trim_end().strip_suffix('}').unwrap(),
+        exports.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    byond_fn!(fn byond_test_panic(message) {
+        if !message.is_empty() {
+            panic!("{}", message);
+        }
+        Some("fine")
+    });
+
+    fn call(message: &str) -> String {
+        let arg = CString::new(message).unwrap();
+        let argv = [arg.as_ptr()];
+        unsafe { CStr::from_ptr(byond_test_panic(1, argv.as_ptr())) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn panics_are_caught() {
+        assert_eq!(call(""), "fine");
+        assert_eq!(call("expected panic"), PANIC_RESULT);
+        assert_eq!(call(""), "fine");
+    }
+}
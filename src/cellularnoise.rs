@@ -1,4 +1,4 @@
-use crate::error::Result;
+use crate::error::{envelope, Result};
 use rand::*;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use std::fmt::Write;
@@ -7,6 +7,10 @@ byond_fn!(fn cnoise_generate(percentage, smoothing_iterations, birth_limit, deat
     noise_gen(percentage, smoothing_iterations, birth_limit, death_limit, width, height).ok()
 });
 
+byond_fn!(fn cnoise_generate_v2(percentage, smoothing_iterations, birth_limit, death_limit, width, height) {
+    Some(envelope(noise_gen(percentage, smoothing_iterations, birth_limit, death_limit, width, height)))
+});
+
 fn noise_gen(
     percentage_as_str: &str,
     smoothing_level_as_str: &str,
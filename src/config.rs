@@ -0,0 +1,232 @@
+//! Runtime configuration, read from `rust_g.toml` (or the file named by the
+//! `RUST_G_CONFIG` environment variable) the first time anything asks for it.
+//!
+//! Every setting is optional; anything left out keeps the subsystem's built in
+//! default. Subsystems read their settings when they start up, so changes made
+//! later through `rustg_configure` only affect what starts afterwards (except
+//! for the job pool, which is resized immediately).
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+
+#[cfg(feature = "jobs")]
+use std::collections::HashMap;
+
+const DEFAULT_PATH: &str = "rust_g.toml";
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[cfg(feature = "http")]
+    pub http: HttpConfig,
+    #[cfg(feature = "jobs")]
+    pub jobs: JobsConfig,
+    #[cfg(feature = "log")]
+    pub log: LogConfig,
+    #[cfg(feature = "redis_pubsub")]
+    pub redis_pubsub: RedisPubSubConfig,
+    #[cfg(feature = "sql")]
+    pub sql: SqlConfig,
+}
+
+#[cfg(feature = "http")]
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub user_agent: Option<String>,
+    /// Seconds before a whole request gives up.
+    pub timeout: Option<f32>,
+    /// Seconds before giving up on connecting.
+    pub connect_timeout: Option<f32>,
+}
+
+#[cfg(feature = "jobs")]
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    pub workers: Option<usize>,
+    /// Concurrency limit per job queue (`sql`, `http`, ...).
+    pub limits: HashMap<String, Option<usize>>,
+}
+
+#[cfg(feature = "log")]
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// A `chrono` format string.
+    pub timestamp_format: Option<String>,
+}
+
+#[cfg(feature = "redis_pubsub")]
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedisPubSubConfig {
+    /// How many messages may queue up in each direction.
+    pub channel_capacity: Option<usize>,
+}
+
+#[cfg(feature = "sql")]
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SqlConfig {
+    /// Used when `sql_connect_pool` isn't given `min_threads`.
+    pub min_threads: Option<usize>,
+    /// Used when `sql_connect_pool` isn't given `max_threads`.
+    pub max_threads: Option<usize>,
+    /// Seconds a transaction may sit unused before it is rolled back.
+    pub transaction_timeout: Option<f32>,
+    /// Seconds a cursor may sit unused before it is closed.
+    pub cursor_timeout: Option<f32>,
+    /// File to record queries in. Nothing is recorded if unset, or if the
+    /// `log` feature isn't built in.
+    pub query_log: Option<String>,
+    /// Seconds a query must take to be recorded in `query_log`.
+    pub slow_query_threshold: Option<f32>,
+}
+
+struct State {
+    config: Config,
+    load_error: Option<String>,
+}
+
+static STATE: Lazy<RwLock<State>> = Lazy::new(|| RwLock::new(load()));
+
+fn load() -> State {
+    let path = std::env::var("RUST_G_CONFIG").unwrap_or_else(|_| DEFAULT_PATH.to_owned());
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return State {
+                config: Config::default(),
+                load_error: None,
+            }
+        }
+        Err(e) => {
+            return State {
+                config: Config::default(),
+                load_error: Some(format!("{}: {}", path, e)),
+            }
+        }
+    };
+    let config = toml_dep::from_str(&contents)
+        .map_err(|e| e.to_string())
+        .and_then(|config| validate(&config).map(|()| config));
+    match config {
+        Ok(config) => State {
+            config,
+            load_error: None,
+        },
+        Err(e) => State {
+            config: Config::default(),
+            load_error: Some(format!("{}: {}", path, e)),
+        },
+    }
+}
+
+// Catches settings which parse but would fail where they're used, often on a
+// thread with no way to report it.
+#[cfg_attr(not(any(feature = "http", feature = "log")), allow(unused_variables))]
+fn validate(config: &Config) -> Result<(), String> {
+    #[cfg(feature = "http")]
+    if let Some(user_agent) = &config.http.user_agent {
+        reqwest::header::HeaderValue::from_str(user_agent)
+            .map_err(|e| format!("http.user_agent: {}", e))?;
+    }
+    #[cfg(feature = "log")]
+    if let Some(format) = &config.log.timestamp_format {
+        use chrono::format::{Item, StrftimeItems};
+        if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+            return Err(format!("log.timestamp_format: invalid format {:?}", format));
+        }
+    }
+    Ok(())
+}
+
+/// Runs `f` with the current configuration.
+pub fn read<T>(f: impl FnOnce(&Config) -> T) -> T {
+    f(&STATE.read().unwrap().config)
+}
+
+// Values in `patch` replace those in `base`, recursing into tables.
+fn merge(base: &mut serde_json::Value, patch: serde_json::Value) {
+    match (base, patch) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge(base.entry(key).or_insert(serde_json::Value::Null), value);
+            }
+        }
+        (base, patch) => *base = patch,
+    }
+}
+
+fn configure(json: &str) -> Result<(), String> {
+    let patch: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let mut state = STATE.write().unwrap();
+    let mut merged = serde_json::to_value(&state.config).map_err(|e| e.to_string())?;
+    merge(&mut merged, patch);
+    let config = serde_json::from_value(merged).map_err(|e| e.to_string())?;
+    validate(&config)?;
+    state.config = config;
+    // The pool reads the config when it's first created, so the lock has to
+    // be released before touching it.
+    #[cfg(feature = "jobs")]
+    let jobs = state.config.jobs.clone();
+    drop(state);
+    #[cfg(feature = "jobs")]
+    crate::jobs::apply_config(jobs);
+    Ok(())
+}
+
+// Overrides settings from a JSON object shaped like `rust_g.toml`. Returns an
+// empty string on success.
+byond_fn!(fn rustg_configure(json) {
+    configure(json).err()
+});
+
+// The settings currently in effect, plus `load_error` if the config file
+// couldn't be read.
+byond_fn!(
+    fn rustg_get_config() {
+        let state = STATE.read().unwrap();
+        let mut value = serde_json::to_value(&state.config).ok()?;
+        if let (Some(error), Some(object)) = (&state.load_error, value.as_object_mut()) {
+            object.insert("load_error".to_owned(), error.clone().into());
+        }
+        Some(value.to_string())
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merge_replaces_leaves_only() {
+        let mut base = json!({"sql": {"min_threads": 1, "max_threads": 10}, "log": {}});
+        merge(
+            &mut base,
+            json!({"sql": {"max_threads": 20}, "http": {"timeout": 5}}),
+        );
+        assert_eq!(
+            base,
+            json!({
+                "sql": {"min_threads": 1, "max_threads": 20},
+                "log": {},
+                "http": {"timeout": 5},
+            })
+        );
+    }
+
+    #[cfg(all(feature = "http", feature = "log"))]
+    #[test]
+    fn unusable_settings_are_rejected() {
+        let mut config = Config::default();
+        assert!(validate(&config).is_ok());
+        config.log.timestamp_format = Some("%F %Q".to_owned());
+        assert!(validate(&config).is_err());
+        config.log.timestamp_format = Some("%F %T".to_owned());
+        config.http.user_agent = Some("bad\nagent".to_owned());
+        assert!(validate(&config).is_err());
+    }
+}
@@ -1,10 +1,14 @@
-use crate::error::Result;
+use crate::error::{envelope, Result};
 use dbpnoise::gen_noise;
 
 byond_fn!(fn dbp_generate(seed, accuracy, stamp_size, world_size, lower_range, upper_range) {
     gen_dbp_noise(seed, accuracy, stamp_size, world_size, lower_range, upper_range).ok()
 });
 
+byond_fn!(fn dbp_generate_v2(seed, accuracy, stamp_size, world_size, lower_range, upper_range) {
+    Some(envelope(gen_dbp_noise(seed, accuracy, stamp_size, world_size, lower_range, upper_range)))
+});
+
 fn gen_dbp_noise(
     seed: &str,
     accuracy_as_str: &str,
@@ -1,4 +1,4 @@
-use crate::error::{Error, Result};
+use crate::error::{envelope, Error, Result};
 use png::{Decoder, Encoder, OutputInfo, Reader};
 use std::{
     fs::{create_dir_all, File},
@@ -14,16 +14,33 @@ byond_fn!(fn dmi_create_png(path, width, height, data) {
 });
 
 byond_fn!(fn dmi_resize_png(path, width, height, resizetype) {
-    let resizetype = match resizetype {
+    resize_png(path, width, height, resize_type(resizetype)).err()
+});
+
+byond_fn!(fn dmi_strip_metadata_v2(path) {
+    Some(envelope(strip_metadata(path).map(|()| serde_json::Value::Null)))
+});
+
+byond_fn!(fn dmi_create_png_v2(path, width, height, data) {
+    Some(envelope(create_png(path, width, height, data).map(|()| serde_json::Value::Null)))
+});
+
+byond_fn!(fn dmi_resize_png_v2(path, width, height, resizetype) {
+    Some(envelope(
+        resize_png(path, width, height, resize_type(resizetype)).map(|()| serde_json::Value::Null),
+    ))
+});
+
+fn resize_type(name: &str) -> image::imageops::FilterType {
+    match name {
         "catmull" => image::imageops::CatmullRom,
         "gaussian" => image::imageops::Gaussian,
         "lanczos3" => image::imageops::Lanczos3,
         "nearest" => image::imageops::Nearest,
         "triangle" => image::imageops::Triangle,
         _ => image::imageops::Nearest,
-    };
-    resize_png(path, width, height, resizetype).err()
-});
+    }
+}
 
 fn strip_metadata(path: &str) -> Result<()> {
     let (reader, frame_info, image) = read_png(path)?;
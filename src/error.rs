@@ -34,7 +34,7 @@ pub enum Error {
     #[cfg(feature = "png")]
     #[error(transparent)]
     ImageEncoding(#[from] EncodingError),
-    #[cfg(feature = "http")]
+    #[cfg(feature = "serde_json")]
     #[error(transparent)]
     JsonSerialization(#[from] serde_json::Error),
     #[error(transparent)]
@@ -49,6 +49,9 @@ pub enum Error {
     #[cfg(feature = "http")]
     #[error(transparent)]
     Request(#[from] reqwest::Error),
+    #[cfg(feature = "http")]
+    #[error("Request body can't be sent again.")]
+    RequestNotCloneable,
     #[cfg(feature = "toml")]
     #[error(transparent)]
     TomlDeserialization(#[from] toml_dep::de::Error),
@@ -58,12 +61,142 @@ pub enum Error {
     #[cfg(feature = "unzip")]
     #[error(transparent)]
     Unzip(#[from] ZipError),
+    #[cfg(feature = "git")]
+    #[error(transparent)]
+    Git(#[from] git2::Error),
     #[cfg(feature = "hash")]
     #[error("Unable to decode hex value.")]
     HexDecode,
     #[cfg(feature = "influxdb2")]
     #[error("Invalid metrics format")]
     InvalidMetrics,
+    #[cfg(feature = "sql")]
+    #[error(transparent)]
+    Sql(#[from] mysql::Error),
+    #[cfg(feature = "sql")]
+    #[error("Length of row was smaller than column count.")]
+    SqlRowLength,
+    #[cfg(feature = "sql")]
+    #[error("Invalid SQL parameter: {0}")]
+    SqlParam(String),
+    #[cfg(feature = "sql")]
+    #[error("Migration failed: {0}")]
+    SqlMigration(String),
+    #[cfg(feature = "sql")]
+    #[error("Database unreachable, trying again in {0:.1} seconds.")]
+    SqlReconnecting(f32),
+    #[cfg(feature = "sql")]
+    #[error("Query timed out after {0} seconds.")]
+    SqlTimedOut(f32),
+    #[cfg(feature = "sql")]
+    #[error("Query was cancelled.")]
+    SqlCancelled,
+    #[cfg(feature = "sql")]
+    #[error("Not supported by this database backend.")]
+    SqlUnsupported,
+    #[cfg(feature = "sql")]
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[cfg(feature = "sql_postgres")]
+    #[error(transparent)]
+    Postgres(#[from] postgres::Error),
+    #[cfg(feature = "sql_postgres")]
+    #[error(transparent)]
+    Tls(#[from] native_tls::Error),
+    #[cfg(feature = "sql_sqlite")]
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+impl Error {
+    /// A short, stable name for the kind of error, for DM code to match on.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Null => "null",
+            Error::Utf8 { .. } => "utf8",
+            Error::InvalidFilename => "invalid_filename",
+            Error::Io(_) => "io",
+            Error::InvalidAlgorithm => "invalid_algorithm",
+            #[cfg(feature = "png")]
+            Error::ImageDecoding(_) => "image_decoding",
+            #[cfg(feature = "png")]
+            Error::ImageEncoding(_) => "image_encoding",
+            #[cfg(feature = "serde_json")]
+            Error::JsonSerialization(_) => "json",
+            Error::ParseInt(_) => "parse_int",
+            Error::ParseFloat(_) => "parse_float",
+            Error::GenericImage(_) => "image",
+            #[cfg(feature = "png")]
+            Error::InvalidPngData => "invalid_png_data",
+            #[cfg(feature = "http")]
+            Error::Request(_) => "request",
+            #[cfg(feature = "http")]
+            Error::RequestNotCloneable => "request",
+            #[cfg(feature = "toml")]
+            Error::TomlDeserialization(_) => "toml_deserialization",
+            #[cfg(feature = "toml")]
+            Error::TomlSerialization(_) => "toml_serialization",
+            #[cfg(feature = "unzip")]
+            Error::Unzip(_) => "unzip",
+            #[cfg(feature = "git")]
+            Error::Git(_) => "git",
+            #[cfg(feature = "hash")]
+            Error::HexDecode => "hex_decode",
+            #[cfg(feature = "influxdb2")]
+            Error::InvalidMetrics => "invalid_metrics",
+            #[cfg(feature = "sql")]
+            Error::Sql(_) => "sql",
+            #[cfg(feature = "sql")]
+            Error::SqlRowLength => "sql",
+            #[cfg(feature = "sql")]
+            Error::SqlParam(_) => "sql",
+            #[cfg(feature = "sql")]
+            Error::SqlMigration(_) => "sql",
+            #[cfg(feature = "sql")]
+            Error::SqlReconnecting(_) => "sql",
+            #[cfg(feature = "sql")]
+            Error::SqlTimedOut(_) => "sql_timeout",
+            #[cfg(feature = "sql")]
+            Error::SqlCancelled => "sql_cancelled",
+            #[cfg(feature = "sql")]
+            Error::SqlUnsupported => "sql",
+            #[cfg(feature = "sql")]
+            Error::Csv(_) => "csv",
+            #[cfg(feature = "sql_postgres")]
+            Error::Postgres(_) => "sql",
+            #[cfg(feature = "sql_postgres")]
+            Error::Tls(_) => "sql",
+            #[cfg(feature = "sql_sqlite")]
+            Error::Sqlite(_) => "sql",
+        }
+    }
+}
+
+/// Builds the `{ok, data, error: {kind, message}}` envelope returned by the
+/// `_v2` exports, so DM can handle every failure the same way.
+///
+/// Each export that can fail has a `_v2` variant which wraps its result in
+/// this, leaving the original's return value as it was. Ones which can't fail
+/// (`file_exists`, `url_encode`, `json_is_valid`, the timers and so on) have
+/// nothing to wrap, and don't get one.
+#[cfg(feature = "serde_json")]
+pub fn envelope<T: Into<serde_json::Value>>(result: Result<T>) -> String {
+    match result {
+        Ok(data) => serde_json::json!({
+            "ok": true,
+            "data": data.into(),
+            "error": null,
+        }),
+        Err(error) => serde_json::json!({
+            "ok": false,
+            "data": null,
+            "error": {
+                "kind": error.kind(),
+                "message": error.to_string(),
+            },
+        }),
+    }
+    .to_string()
 }
 
 impl From<Utf8Error> for Error {
@@ -1,4 +1,4 @@
-use crate::error::Result;
+use crate::error::{envelope, Error, Result};
 use std::{
     fs::{File, OpenOptions},
     io::{BufRead, BufReader, BufWriter, Read, Write},
@@ -28,7 +28,32 @@ byond_fn!(fn file_seek_line(path, line) {
     seek_line(path, match line.parse::<usize>() {
         Ok(line) => line,
         Err(_) => return None,
-    })
+    }).ok().flatten()
+});
+
+byond_fn!(fn file_read_v2(path) {
+    Some(envelope(read(path)))
+});
+
+byond_fn!(fn file_write_v2(data, path) {
+    Some(envelope(write(data, path)))
+});
+
+byond_fn!(fn file_append_v2(data, path) {
+    Some(envelope(append(data, path)))
+});
+
+byond_fn!(fn file_get_line_count_v2(path) {
+    Some(envelope(get_line_count(path)))
+});
+
+// `data` is null past the end of the file.
+byond_fn!(fn file_seek_line_v2(path, line) {
+    Some(envelope(
+        line.parse::<usize>()
+            .map_err(Error::from)
+            .and_then(|line| seek_line(path, line)),
+    ))
 });
 
 fn read(path: &str) -> Result<String> {
@@ -87,7 +112,7 @@ fn get_line_count(path: &str) -> Result<u32> {
     Ok(file.lines().count() as u32)
 }
 
-fn seek_line(path: &str, line: usize) -> Option<String> {
-    let file = BufReader::new(File::open(path).ok()?);
-    file.lines().nth(line).and_then(|line| line.ok())
+fn seek_line(path: &str, line: usize) -> Result<Option<String>> {
+    let file = BufReader::new(File::open(path)?);
+    Ok(file.lines().nth(line).transpose()?)
 }
@@ -1,24 +1,49 @@
+use crate::error::{envelope, Result};
 use chrono::{TimeZone, Utc};
-use git2::{Error, ErrorCode, Repository};
+use git2::{Error, Repository};
 
 thread_local! {
-    static REPOSITORY: Result<Repository, Error> = Repository::open(".");
+    static REPOSITORY: std::result::Result<Repository, Error> = Repository::open(".");
 }
 
 byond_fn!(fn rg_git_revparse(rev) {
-    REPOSITORY.with(|repo| -> Result<String, ErrorCode> {
-        let repo = repo.as_ref().map_err(Error::code)?;
-        let object = repo.revparse_single(rev).map_err(|e| e.code())?;
-        Ok(object.id().to_string())
-    }).ok()
+    revparse(rev).ok()
 });
 
 byond_fn!(fn rg_git_commit_date(rev) {
-    REPOSITORY.with(|repo| -> Result<String, ErrorCode> {
-        let repo = repo.as_ref().map_err(Error::code)?;
-        let object = repo.revparse_single(rev).map_err(|e| e.code())?;
-        let commit = object.as_commit().ok_or(ErrorCode::GenericError)?;
-        let datetime = Utc.timestamp(commit.time().seconds(), 0);
-        Ok(datetime.format("%F").to_string())
-    }).ok()
+    commit_date(rev).ok()
+});
+
+byond_fn!(fn rg_git_revparse_v2(rev) {
+    Some(envelope(revparse(rev)))
 });
+
+byond_fn!(fn rg_git_commit_date_v2(rev) {
+    Some(envelope(commit_date(rev)))
+});
+
+fn with_repository<T>(f: impl FnOnce(&Repository) -> std::result::Result<T, Error>) -> Result<T> {
+    REPOSITORY.with(|repo| match repo {
+        Ok(repo) => Ok(f(repo)?),
+        // Not `Clone`, so each caller gets a copy.
+        Err(e) => Err(Error::new(e.code(), e.class(), e.message()).into()),
+    })
+}
+
+fn revparse(rev: &str) -> Result<String> {
+    with_repository(|repo| Ok(repo.revparse_single(rev)?.id().to_string()))
+}
+
+fn commit_date(rev: &str) -> Result<String> {
+    with_repository(|repo| {
+        let object = repo.revparse_single(rev)?;
+        let commit = object
+            .as_commit()
+            .ok_or_else(|| Error::from_str("Not a commit."))?;
+        let datetime = Utc
+            .timestamp_opt(commit.time().seconds(), 0)
+            .single()
+            .ok_or_else(|| Error::from_str("Commit time out of range."))?;
+        Ok(datetime.format("%F").to_string())
+    })
+}
@@ -1,4 +1,4 @@
-use crate::error::{Error, Result};
+use crate::error::{envelope, Error, Result};
 use const_random::const_random;
 const XXHASH_SEED: u64 = const_random!(u64);
 use md5::Md5;
@@ -39,6 +39,27 @@ byond_fn!(fn generate_totp_tolerance(hex_seed, tolerance) {
     }
 });
 
+byond_fn!(fn hash_string_v2(algorithm, string) {
+    Some(envelope(string_hash(algorithm, string)))
+});
+
+byond_fn!(fn hash_file_v2(algorithm, string) {
+    Some(envelope(file_hash(algorithm, string)))
+});
+
+byond_fn!(fn generate_totp_v2(hex_seed) {
+    Some(envelope(totp_generate(hex_seed, 0, None)))
+});
+
+byond_fn!(fn generate_totp_tolerance_v2(hex_seed, tolerance) {
+    Some(envelope(
+        tolerance
+            .parse::<i32>()
+            .map_err(Error::from)
+            .and_then(|tolerance| totp_codes(hex_seed, tolerance, None)),
+    ))
+});
+
 fn hash_algorithm<B: AsRef<[u8]>>(name: &str, bytes: B) -> Result<String> {
     match name {
         "md5" => {
@@ -90,12 +111,20 @@ fn totp_generate_tolerance(
     tolerance: i32,
     time_override: Option<i64>,
 ) -> Result<String> {
+    Ok(serde_json::to_string(&totp_codes(
+        hex_seed,
+        tolerance,
+        time_override,
+    )?)?)
+}
+
+fn totp_codes(hex_seed: &str, tolerance: i32, time_override: Option<i64>) -> Result<Vec<String>> {
     let mut results: Vec<String> = Vec::new();
     for i in -tolerance..(tolerance + 1) {
         let result = totp_generate(hex_seed, i.try_into().unwrap(), time_override)?;
         results.push(result)
     }
-    Ok(serde_json::to_string(&results)?)
+    Ok(results)
 }
 
 /// Generates a single TOTP code from 20 character hex_seed, offset by offset time steps
@@ -1,8 +1,15 @@
-use crate::{error::Result, jobs};
+use crate::{
+    config,
+    error::{envelope, Error, Result},
+    jobs,
+};
 use once_cell::sync::Lazy;
+use reqwest::blocking::{Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
 
 // ----------------------------------------------------------------------------
 // Interface
@@ -13,6 +20,24 @@ struct RequestOptions {
     output_filename: Option<String>,
     #[serde(default)]
     body_filename: Option<String>,
+    // Seconds, in place of the configured ones.
+    #[serde(default)]
+    timeout: Option<f32>,
+    #[serde(default)]
+    connect_timeout: Option<f32>,
+    // Times to try again after a connection error, 5xx or 429.
+    #[serde(default)]
+    retries: u32,
+    // Whether a 5xx to a POST or PATCH is retried too, which is only safe if
+    // the endpoint doesn't act on a request twice.
+    #[serde(default)]
+    retry_non_idempotent: bool,
+    // Seconds before the first retry, doubling for each one after.
+    #[serde(default)]
+    retry_backoff: Option<f32>,
+    // The longest to wait between tries, `Retry-After` included.
+    #[serde(default)]
+    max_retry_backoff: Option<f32>,
 }
 
 #[derive(Serialize)]
@@ -43,7 +68,7 @@ byond_fn!(fn http_request_async(method, url, body, headers, options) {
         Err(e) => return Some(e.to_string())
     };
 
-    Some(jobs::start(move || {
+    Some(jobs::start("http", move || {
         match submit_request(req) {
             Ok(r) => r,
             Err(e) => e.to_string()
@@ -51,6 +76,18 @@ byond_fn!(fn http_request_async(method, url, body, headers, options) {
     }))
 });
 
+// The async one always returns a job id, with any error in the job's result.
+byond_fn!(fn http_request_blocking_v2(method, url, body, headers, options) {
+    Some(envelope(
+        construct_request(method, url, body, headers, options).and_then(perform_request),
+    ))
+});
+
+byond_fn!(fn http_request_async_v2(method, url, body, headers, options) {
+    let req = construct_request(method, url, body, headers, options);
+    Some(jobs::start("http", move || envelope(req.and_then(perform_request))))
+});
+
 // If the response can be deserialized -> success.
 // If the response can't be deserialized -> failure or WIP.
 byond_fn!(fn http_check_request(id) {
@@ -60,32 +97,82 @@ byond_fn!(fn http_check_request(id) {
 // ----------------------------------------------------------------------------
 // Shared HTTP client state
 
-const VERSION: &str = env!("CARGO_PKG_VERSION");
-const PKG_NAME: &str = env!("CARGO_PKG_NAME");
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
-fn setup_http_client() -> reqwest::blocking::Client {
-    use reqwest::{
-        blocking::Client,
-        header::{HeaderMap, USER_AGENT},
-    };
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
 
+// Clients kept for connect timeouts other than the configured one. Past this,
+// requests with yet another timeout get a client of their own.
+const MAX_CONNECT_TIMEOUT_CLIENTS: usize = 8;
+
+fn setup_http_client() -> Client {
+    build_client(None)
+}
+
+// reqwest only takes a connect timeout per client, so requests giving their
+// own get a client for it, kept for the next request using the same one.
+fn client_with_connect_timeout(timeout: Duration) -> Client {
+    static CLIENTS: Lazy<Mutex<HashMap<Duration, Client>>> = Lazy::new(Default::default);
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some(client) = clients.get(&timeout) {
+        return client.clone();
+    }
+    let client = build_client(Some(timeout));
+    if clients.len() < MAX_CONNECT_TIMEOUT_CLIENTS {
+        clients.insert(timeout, client.clone());
+    }
+    client
+}
+
+fn build_client(connect_timeout: Option<Duration>) -> Client {
+    use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
+
+    let config = config::read(|c| c.http.clone());
+    // Checked when the config is loaded, but a panic here would take every
+    // later request down with it.
+    let user_agent = config
+        .user_agent
+        .as_deref()
+        .and_then(|user_agent| HeaderValue::from_str(user_agent).ok())
+        .unwrap_or_else(|| HeaderValue::from_static(DEFAULT_USER_AGENT));
     let mut headers = HeaderMap::new();
-    headers.insert(
-        USER_AGENT,
-        format!("{}/{}", PKG_NAME, VERSION).parse().unwrap(),
-    );
+    headers.insert(USER_AGENT, user_agent);
+
+    let mut builder = Client::builder().default_headers(headers);
+    if let Some(timeout) = seconds(config.timeout) {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = connect_timeout.or_else(|| seconds(config.connect_timeout)) {
+        builder = builder.connect_timeout(timeout);
+    }
+    builder.build().unwrap()
+}
 
-    Client::builder().default_headers(headers).build().unwrap()
+// Negative or otherwise unusable lengths are ignored.
+fn seconds(seconds: Option<f32>) -> Option<Duration> {
+    seconds.and_then(|s| Duration::try_from_secs_f32(s).ok())
 }
 
-pub static HTTP_CLIENT: Lazy<reqwest::blocking::Client> = Lazy::new(setup_http_client);
+pub static HTTP_CLIENT: Lazy<Client> = Lazy::new(setup_http_client);
 
 // ----------------------------------------------------------------------------
 // Request construction and execution
 
 pub struct RequestPrep {
-    req: reqwest::blocking::RequestBuilder,
+    // Without the body when it is read from a file, which is opened again for
+    // each try.
+    req: RequestBuilder,
+    body_filename: Option<String>,
+    // Cloning the request loses its timeout, so it is kept here instead.
+    timeout: Option<Duration>,
     output_filename: Option<String>,
+    retries: u32,
+    // Whether a 5xx is worth retrying, which it isn't for a POST or PATCH
+    // unless the caller says so.
+    retry_server_errors: bool,
+    retry_backoff: Duration,
+    max_retry_backoff: Duration,
 }
 
 pub fn construct_request(
@@ -95,13 +182,23 @@ pub fn construct_request(
     headers: &str,
     options: &str,
 ) -> Result<RequestPrep> {
+    let options: Option<RequestOptions> = if options.is_empty() {
+        None
+    } else {
+        Some(serde_json::from_str(options)?)
+    };
+    let client = match options.as_ref().and_then(|o| seconds(o.connect_timeout)) {
+        Some(timeout) => client_with_connect_timeout(timeout),
+        None => HTTP_CLIENT.clone(),
+    };
+
     let mut req = match method {
-        "post" => HTTP_CLIENT.post(url),
-        "put" => HTTP_CLIENT.put(url),
-        "patch" => HTTP_CLIENT.patch(url),
-        "delete" => HTTP_CLIENT.delete(url),
-        "head" => HTTP_CLIENT.head(url),
-        _ => HTTP_CLIENT.get(url),
+        "post" => client.post(url),
+        "put" => client.put(url),
+        "patch" => client.patch(url),
+        "delete" => client.delete(url),
+        "head" => client.head(url),
+        _ => client.get(url),
     };
 
     if !body.is_empty() {
@@ -115,23 +212,86 @@ pub fn construct_request(
         }
     }
 
-    let mut output_filename = None;
-    if !options.is_empty() {
-        let options: RequestOptions = serde_json::from_str(options)?;
-        output_filename = options.output_filename;
+    let mut prep = RequestPrep {
+        req,
+        body_filename: None,
+        timeout: None,
+        output_filename: None,
+        retries: 0,
+        retry_server_errors: !matches!(method, "post" | "patch"),
+        retry_backoff: DEFAULT_RETRY_BACKOFF,
+        max_retry_backoff: DEFAULT_MAX_RETRY_BACKOFF,
+    };
+    if let Some(options) = options {
+        prep.timeout = seconds(options.timeout);
         if let Some(fname) = options.body_filename {
-            req = req.body(std::fs::File::open(fname)?);
+            // Opened here too, so a missing file fails before anything is sent.
+            std::fs::File::open(&fname)?;
+            prep.body_filename = Some(fname);
         }
+        prep.output_filename = options.output_filename;
+        prep.retries = options.retries;
+        prep.retry_server_errors |= options.retry_non_idempotent;
+        prep.retry_backoff = seconds(options.retry_backoff).unwrap_or(DEFAULT_RETRY_BACKOFF);
+        prep.max_retry_backoff =
+            seconds(options.max_retry_backoff).unwrap_or(DEFAULT_MAX_RETRY_BACKOFF);
     }
+    Ok(prep)
+}
 
-    Ok(RequestPrep {
-        req,
-        output_filename,
+pub fn submit_request(prep: RequestPrep) -> Result<String> {
+    Ok(serde_json::to_string(&perform_request(prep)?)?)
+}
+
+// Sends the request, trying again while that is allowed and worth doing.
+fn send_request(prep: &RequestPrep) -> Result<reqwest::blocking::Response> {
+    let mut backoff = prep.retry_backoff;
+    for _ in 0..prep.retries {
+        let wait = match try_request(prep)?.send() {
+            Ok(response) if is_retryable(prep, response.status()) => {
+                retry_after(&response).unwrap_or(backoff)
+            }
+            Err(e) if e.is_connect() => backoff,
+            result => return Ok(result?),
+        };
+        std::thread::sleep(wait.min(prep.max_retry_backoff));
+        backoff = backoff.saturating_mul(2);
+    }
+    Ok(try_request(prep)?.send()?)
+}
+
+fn try_request(prep: &RequestPrep) -> Result<RequestBuilder> {
+    // Only a streamed body can't be cloned, and file bodies are added here.
+    let mut req = prep.req.try_clone().ok_or(Error::RequestNotCloneable)?;
+    if let Some(timeout) = prep.timeout {
+        req = req.timeout(timeout);
+    }
+    Ok(match &prep.body_filename {
+        Some(fname) => req.body(std::fs::File::open(fname)?),
+        None => req,
     })
 }
 
-pub fn submit_request(prep: RequestPrep) -> Result<String> {
-    let mut response = prep.req.send()?;
+// A 429 means the server didn't act on the request, so it is always safe.
+fn is_retryable(prep: &RequestPrep, status: reqwest::StatusCode) -> bool {
+    (status.is_server_error() && prep.retry_server_errors)
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+// Only the delay in seconds form, not an HTTP date.
+fn retry_after(response: &reqwest::blocking::Response) -> Option<Duration> {
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?;
+    value
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+fn perform_request(prep: RequestPrep) -> Result<serde_json::Value> {
+    let mut response = send_request(&prep)?;
 
     let body;
     let mut resp = Response {
@@ -156,5 +316,5 @@ pub fn submit_request(prep: RequestPrep) -> Result<String> {
         resp.body = Some(&body);
     }
 
-    Ok(serde_json::to_string(&resp)?)
+    Ok(serde_json::to_value(&resp)?)
 }
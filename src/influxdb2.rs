@@ -10,7 +10,7 @@ byond_fn!(
         let data = data.to_owned();
         let endpoint = endpoint.to_owned();
         let token = token.to_owned();
-        Some(jobs::start(move || {
+        Some(jobs::start("influxdb2", move || {
             fn handle(data: &str, endpoint: &str, token: &str) -> Result<RequestPrep, Error> {
                 let mut lines = vec!();
 
@@ -75,7 +75,7 @@ byond_fn!(
         let endpoint = endpoint.to_owned();
         let token = token.to_owned();
         let round_id = round_id.to_owned();
-        Some(jobs::start(move || {
+        Some(jobs::start("influxdb2", move || {
             fn handle(data: &str, endpoint: &str, token: &str, round_id: &str) -> Result<RequestPrep, Error> {
                 let mut lines = vec!();
 
@@ -1,14 +1,45 @@
 //! Job system
+use crate::config;
 use flume::Receiver;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::json;
 use std::{
     cell::RefCell,
-    collections::hash_map::{Entry, HashMap},
+    collections::{
+        hash_map::{Entry, HashMap},
+        VecDeque,
+    },
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
     thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 struct Job {
     rx: Receiver<Output>,
-    handle: thread::JoinHandle<()>,
+    queue: String,
+    // Its task's place in the pool, to take it off the queue if it's dropped.
+    seq: u64,
+    started: Instant,
+    started_at: SystemTime,
+    deadline: Option<Instant>,
+    // When the job was first seen to have a result (or to have timed out)
+    // that nobody has collected yet.
+    settled: Option<Instant>,
+}
+
+impl Job {
+    fn timed_out(&self, now: Instant) -> bool {
+        matches!(self.deadline, Some(deadline) if now >= deadline)
+    }
+
+    fn is_settled(&self, now: Instant) -> bool {
+        !self.rx.is_empty() || self.rx.is_disconnected() || self.timed_out(now)
+    }
 }
 
 type Output = String;
@@ -17,26 +48,58 @@ type JobId = String;
 const NO_RESULTS_YET: &str = "NO RESULTS YET";
 const NO_SUCH_JOB: &str = "NO SUCH JOB";
 const JOB_PANICKED: &str = "JOB PANICKED";
+const JOB_TIMED_OUT: &str = "JOB TIMED OUT";
+const JOB_CANCELLED: &str = "JOB CANCELLED";
+
+// Results nobody has collected this long after the job settled are dropped,
+// so the map doesn't grow forever when DM forgets about a job.
+const UNCOLLECTED_RESULT_LIFETIME: Duration = Duration::from_secs(10 * 60);
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Default)]
 struct Jobs {
     map: HashMap<JobId, Job>,
     next_job: usize,
+    last_reap: Option<Instant>,
 }
 
 impl Jobs {
-    fn start<F: FnOnce() -> Output + Send + 'static>(&mut self, f: F) -> JobId {
+    fn start<F: FnOnce() -> Output + Send + 'static>(&mut self, queue: &str, f: F) -> JobId {
+        self.reap();
         let (tx, rx) = flume::unbounded();
-        let handle = thread::spawn(move || {
-            let _ = tx.send(f());
-        });
+        let seq = POOL.submit(
+            queue,
+            Box::new(move || {
+                // Its job was dropped without being taken off the queue, as
+                // when the thread which started it has gone.
+                if tx.is_disconnected() {
+                    STATS.skipped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                let _ = tx.send(f());
+                STATS.finished.fetch_add(1, Ordering::Relaxed);
+            }),
+        );
         let id = self.next_job.to_string();
         self.next_job += 1;
-        self.map.insert(id.clone(), Job { rx, handle });
+        STATS.started.fetch_add(1, Ordering::Relaxed);
+        self.map.insert(
+            id.clone(),
+            Job {
+                rx,
+                queue: queue.to_owned(),
+                seq,
+                started: Instant::now(),
+                started_at: SystemTime::now(),
+                deadline: None,
+                settled: None,
+            },
+        );
         id
     }
 
     fn check(&mut self, id: &str) -> Output {
+        self.reap();
         let entry = match self.map.entry(id.to_owned()) {
             Entry::Occupied(occupied) => occupied,
             Entry::Vacant(_) => return NO_SUCH_JOB.to_owned(),
@@ -44,21 +107,551 @@ impl Jobs {
         let result = match entry.get().rx.try_recv() {
             Ok(result) => result,
             Err(flume::TryRecvError::Disconnected) => JOB_PANICKED.to_owned(),
-            Err(flume::TryRecvError::Empty) => return NO_RESULTS_YET.to_owned(),
+            Err(flume::TryRecvError::Empty) => {
+                if entry.get().timed_out(Instant::now()) {
+                    // A running job can't be stopped, so it is left to
+                    // finish on its own and its result is discarded.
+                    unqueue(&entry.remove());
+                    STATS.timed_out.fetch_add(1, Ordering::Relaxed);
+                    return JOB_TIMED_OUT.to_owned();
+                }
+                return NO_RESULTS_YET.to_owned();
+            }
         };
-        let _ = entry.remove().handle.join();
+        entry.remove();
         result
     }
+
+    fn cancel(&mut self, id: &str) -> Output {
+        // As with timeouts, a running job is detached rather than stopped.
+        match self.map.remove(id) {
+            Some(job) => {
+                unqueue(&job);
+                STATS.cancelled.fetch_add(1, Ordering::Relaxed);
+                JOB_CANCELLED.to_owned()
+            }
+            None => NO_SUCH_JOB.to_owned(),
+        }
+    }
+
+    fn set_timeout(&mut self, id: &str, timeout: Duration) -> Option<Output> {
+        let job = match self.map.get_mut(id) {
+            Some(job) => job,
+            None => return Some(NO_SUCH_JOB.to_owned()),
+        };
+        job.deadline = Some(job.started + timeout);
+        None
+    }
+
+    fn reap(&mut self) {
+        let now = Instant::now();
+        if matches!(self.last_reap, Some(last) if now - last < REAP_INTERVAL) {
+            return;
+        }
+        self.last_reap = Some(now);
+        self.map.retain(|_, job| {
+            if job.settled.is_none() && job.is_settled(now) {
+                job.settled = Some(now);
+            }
+            let keep = match job.settled {
+                Some(settled) => now - settled < UNCOLLECTED_RESULT_LIFETIME,
+                None => true,
+            };
+            if !keep {
+                unqueue(job);
+                STATS.reaped.fetch_add(1, Ordering::Relaxed);
+            }
+            keep
+        });
+    }
+
+    fn list(&self) -> serde_json::Value {
+        let now = Instant::now();
+        let mut jobs: Vec<_> = self.map.iter().collect();
+        jobs.sort_by_key(|(id, _)| id.parse::<usize>().unwrap_or_default());
+        let jobs: Vec<_> = jobs
+            .into_iter()
+            .map(|(id, job)| {
+                json!({
+                    "id": id,
+                    "queue": job.queue,
+                    "started": job
+                        .started_at
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0.0, |d| d.as_secs_f64()),
+                    "elapsed": (now - job.started).as_secs_f64(),
+                    "settled": job.is_settled(now),
+                })
+            })
+            .collect();
+        json!({
+            "jobs": jobs,
+            "started": STATS.started.load(Ordering::Relaxed),
+            "finished": STATS.finished.load(Ordering::Relaxed),
+            "panicked": STATS.panicked.load(Ordering::Relaxed),
+            "timed_out": STATS.timed_out.load(Ordering::Relaxed),
+            "cancelled": STATS.cancelled.load(Ordering::Relaxed),
+            "skipped": STATS.skipped.load(Ordering::Relaxed),
+            "reaped": STATS.reaped.load(Ordering::Relaxed),
+        })
+    }
+}
+
+// Lifetime totals, for admins working out where jobs are going.
+#[derive(Default)]
+struct Stats {
+    started: AtomicUsize,
+    finished: AtomicUsize,
+    panicked: AtomicUsize,
+    timed_out: AtomicUsize,
+    cancelled: AtomicUsize,
+    // Cancelled or timed out before they started, so never run.
+    skipped: AtomicUsize,
+    reaped: AtomicUsize,
+}
+
+static STATS: Lazy<Stats> = Lazy::new(Stats::default);
+
+// Drops a job's task if it hasn't started yet, rather than have it take up a
+// worker only to be thrown away.
+fn unqueue(job: &Job) {
+    if POOL.remove(&job.queue, job.seq) {
+        STATS.skipped.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 thread_local! {
     static JOBS: RefCell<Jobs> = Default::default();
 }
 
-pub fn start<F: FnOnce() -> Output + Send + 'static>(f: F) -> JobId {
-    JOBS.with(|jobs| jobs.borrow_mut().start(f))
+// ----------------------------------------------------------------------------
+// Worker pool
+
+// Enough for a full SQL pool plus some HTTP traffic, without putting hundreds
+// of threads into a 32-bit process.
+const DEFAULT_WORKERS: usize = 16;
+
+// Limits for queues the config says nothing about. HTTP jobs mostly sit
+// waiting on other servers, and SQL cursors hold their worker until closed, so
+// either could otherwise take every worker and hold up everything else.
+const DEFAULT_LIMITS: &[(&str, usize)] = &[("http", 4), ("sql_cursor", 4)];
+
+type Task = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Queue {
+    pending: VecDeque<(u64, Task)>,
+    running: usize,
+    limit: Option<usize>,
+}
+
+impl Queue {
+    fn has_capacity(&self) -> bool {
+        match self.limit {
+            Some(limit) => self.running < limit,
+            None => true,
+        }
+    }
+}
+
+struct PoolState {
+    queues: HashMap<String, Queue>,
+    next_seq: u64,
+    workers: usize,
+    target_workers: usize,
+}
+
+impl PoolState {
+    // Oldest task from any queue which is still under its concurrency limit.
+    fn next_task(&mut self) -> Option<(String, Task)> {
+        let name = self
+            .queues
+            .iter()
+            .filter(|(_, queue)| queue.has_capacity())
+            .filter_map(|(name, queue)| Some((queue.pending.front()?.0, name)))
+            .min()
+            .map(|(_, name)| name.clone())?;
+        let queue = self.queues.get_mut(&name)?;
+        let (_, task) = queue.pending.pop_front()?;
+        queue.running += 1;
+        Some((name, task))
+    }
+}
+
+struct Pool {
+    state: Mutex<PoolState>,
+    changed: Condvar,
+}
+
+static POOL: Lazy<Pool> = Lazy::new(|| {
+    let config = config::read(|c| c.jobs.clone());
+    let defaults = DEFAULT_LIMITS
+        .iter()
+        .map(|&(name, limit)| (name.to_owned(), Some(limit)));
+    let queues = defaults
+        .chain(config.limits)
+        .map(|(name, limit)| {
+            let queue = Queue {
+                limit,
+                ..Default::default()
+            };
+            (name, queue)
+        })
+        .collect();
+    Pool {
+        state: Mutex::new(PoolState {
+            queues,
+            next_seq: 0,
+            workers: 0,
+            target_workers: config.workers.unwrap_or(DEFAULT_WORKERS).max(1),
+        }),
+        changed: Condvar::new(),
+    }
+});
+
+impl Pool {
+    // Gives back the task's sequence number, for `remove`.
+    fn submit(&'static self, queue: &str, task: Task) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state
+            .queues
+            .entry(queue.to_owned())
+            .or_default()
+            .pending
+            .push_back((seq, task));
+        // Workers are only spawned once there is work for them.
+        if state.workers < state.target_workers {
+            state.workers += 1;
+            thread::spawn(move || self.work());
+        }
+        drop(state);
+        self.changed.notify_all();
+        seq
+    }
+
+    // False if the task has already been picked up by a worker.
+    fn remove(&self, queue: &str, seq: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let pending = match state.queues.get_mut(queue) {
+            Some(queue) => &mut queue.pending,
+            None => return false,
+        };
+        let task = match pending.iter().position(|&(s, _)| s == seq) {
+            Some(index) => pending.remove(index),
+            None => return false,
+        };
+        // Whatever the task holds is dropped without the lock.
+        drop(state);
+        drop(task);
+        true
+    }
+
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.workers > state.target_workers {
+                state.workers -= 1;
+                return;
+            }
+            let (name, task) = match state.next_task() {
+                Some(next) => next,
+                None => {
+                    state = self.changed.wait(state).unwrap();
+                    continue;
+                }
+            };
+            drop(state);
+            // A panicking job drops its sender, which `check` reports.
+            if panic::catch_unwind(AssertUnwindSafe(task)).is_err() {
+                STATS.panicked.fetch_add(1, Ordering::Relaxed);
+            }
+            state = self.state.lock().unwrap();
+            if let Some(queue) = state.queues.get_mut(&name) {
+                queue.running -= 1;
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    fn configure(&'static self, options: PoolOptions) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(workers) = options.workers {
+            state.target_workers = workers.max(1);
+        }
+        for (name, limit) in options.limits {
+            state.queues.entry(name).or_default().limit = limit;
+        }
+        // Top the pool back up to its new size if there is a backlog.
+        let queued: usize = state.queues.values().map(|q| q.pending.len()).sum();
+        while state.workers < state.target_workers && state.workers < queued {
+            state.workers += 1;
+            thread::spawn(move || self.work());
+        }
+        drop(state);
+        self.changed.notify_all();
+    }
+
+    fn depth(&self) -> serde_json::Value {
+        let state = self.state.lock().unwrap();
+        let mut queues = serde_json::Map::new();
+        for (name, queue) in state.queues.iter() {
+            queues.insert(
+                name.clone(),
+                json!({
+                    "queued": queue.pending.len(),
+                    "running": queue.running,
+                    "limit": queue.limit,
+                }),
+            );
+        }
+        json!({
+            "workers": state.workers,
+            "max_workers": state.target_workers,
+            "queued": state.queues.values().map(|q| q.pending.len()).sum::<usize>(),
+            "running": state.queues.values().map(|q| q.running).sum::<usize>(),
+            "queues": queues,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct PoolOptions {
+    workers: Option<usize>,
+    // A `null` limit lifts the limit for that queue.
+    #[serde(default)]
+    limits: HashMap<String, Option<usize>>,
+}
+
+// ----------------------------------------------------------------------------
+// Interface
+
+/// Resizes the pool after `rustg_configure` changes the `[jobs]` settings.
+pub(crate) fn apply_config(config: config::JobsConfig) {
+    POOL.configure(PoolOptions {
+        workers: config.workers,
+        limits: config.limits,
+    });
+}
+
+/// Queues `f` on the shared worker pool. `queue` names the subsystem the job
+/// belongs to, which is what per-subsystem concurrency limits apply to.
+pub fn start<F: FnOnce() -> Output + Send + 'static>(queue: &str, f: F) -> JobId {
+    JOBS.with(|jobs| jobs.borrow_mut().start(queue, f))
+}
+
+/// Runs `f` on the worker pool with no result to collect, for background work
+/// which may be started from any thread. Counts against `queue`'s limit.
+pub(crate) fn spawn<F: FnOnce() + Send + 'static>(queue: &str, f: F) {
+    POOL.submit(queue, Box::new(f));
 }
 
 pub fn check(id: &str) -> String {
     JOBS.with(|jobs| jobs.borrow_mut().check(id))
 }
+
+/// Checks every job in a JSON array of ids at once, giving back a JSON object
+/// of id to `check` result. Ids may be given as strings or numbers.
+pub fn check_many(ids: &str) -> Result<String, serde_json::Error> {
+    let ids: Vec<serde_json::Value> = serde_json::from_str(ids)?;
+    JOBS.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        let mut results = serde_json::Map::new();
+        for id in ids {
+            let id = match id {
+                serde_json::Value::String(s) => s,
+                other => other.to_string(),
+            };
+            let result = jobs.check(&id);
+            results.insert(id, serde_json::Value::String(result));
+        }
+        serde_json::to_string(&results)
+    })
+}
+
+pub fn cancel(id: &str) -> String {
+    JOBS.with(|jobs| jobs.borrow_mut().cancel(id))
+}
+
+byond_fn!(fn jobs_check_many(ids) {
+    Some(match check_many(ids) {
+        Ok(results) => results,
+        Err(e) => e.to_string(),
+    })
+});
+
+byond_fn!(fn job_cancel(id) {
+    Some(cancel(id))
+});
+
+// Makes `check` give up on the job once `seconds` have passed since it was
+// started.
+byond_fn!(fn job_set_timeout(id, seconds) {
+    let timeout = match seconds.parse::<f32>() {
+        Ok(seconds) => match Duration::try_from_secs_f32(seconds) {
+            Ok(timeout) => timeout,
+            Err(e) => return Some(e.to_string()),
+        },
+        Err(e) => return Some(e.to_string()),
+    };
+    JOBS.with(|jobs| jobs.borrow_mut().set_timeout(id, timeout))
+});
+
+// Takes e.g. `{"workers": 16, "limits": {"sql": 8, "http": null}}`.
+byond_fn!(fn jobs_configure(options) {
+    match serde_json::from_str::<PoolOptions>(options) {
+        Ok(options) => {
+            POOL.configure(options);
+            None
+        }
+        Err(e) => Some(e.to_string()),
+    }
+});
+
+byond_fn!(
+    fn jobs_list() {
+        JOBS.with(|jobs| Some(jobs.borrow().list().to_string()))
+    }
+);
+
+byond_fn!(
+    fn jobs_queue_depth() {
+        Some(POOL.depth().to_string())
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn wait_for(id: &str) -> Output {
+        loop {
+            let result = check(id);
+            if result != NO_RESULTS_YET {
+                return result;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn wait_for_settled(id: &str) {
+        while !JOBS.with(|jobs| jobs.borrow().map[id].is_settled(Instant::now())) {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn queue_limit_is_respected() {
+        POOL.configure(PoolOptions {
+            workers: None,
+            limits: [("test_limit".to_owned(), Some(2))].into_iter().collect(),
+        });
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let ids: Vec<_> = (0..8)
+            .map(|_| {
+                let running = running.clone();
+                let peak = peak.clone();
+                start("test_limit", move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    "done".to_owned()
+                })
+            })
+            .collect();
+        for id in ids {
+            assert_eq!(wait_for(&id), "done");
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn default_limits() {
+        let depth = POOL.depth();
+        assert_eq!(depth["queues"]["http"]["limit"], 4, "{}", depth);
+        assert_eq!(depth["queues"]["sql_cursor"]["limit"], 4, "{}", depth);
+    }
+
+    #[test]
+    fn panics_timeouts_and_cancels() {
+        let id = start("test", || panic!("expected"));
+        assert_eq!(wait_for(&id), JOB_PANICKED);
+
+        let id = start("test", || {
+            thread::sleep(Duration::from_millis(200));
+            "late".to_owned()
+        });
+        JOBS.with(|jobs| jobs.borrow_mut().set_timeout(&id, Duration::ZERO));
+        assert_eq!(check(&id), JOB_TIMED_OUT);
+        assert_eq!(check(&id), NO_SUCH_JOB);
+
+        let id = start("test", || "never collected".to_owned());
+        assert_eq!(cancel(&id), JOB_CANCELLED);
+        assert_eq!(check(&id), NO_SUCH_JOB);
+    }
+
+    #[test]
+    fn dropped_jobs_are_taken_off_the_queue() {
+        POOL.configure(PoolOptions {
+            workers: None,
+            limits: [("test_unqueue".to_owned(), Some(1))].into_iter().collect(),
+        });
+        let (release, released) = flume::bounded::<()>(0);
+        let blocker = start("test_unqueue", move || {
+            let _ = released.recv();
+            "released".to_owned()
+        });
+        let ran = Arc::new(AtomicUsize::new(0));
+        let queue = |ran: &Arc<AtomicUsize>| {
+            let ran = ran.clone();
+            start("test_unqueue", move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+                "ran".to_owned()
+            })
+        };
+        let skipped = STATS.skipped.load(Ordering::Relaxed);
+        let cancelled = queue(&ran);
+        assert_eq!(cancel(&cancelled), JOB_CANCELLED);
+        let timed_out = queue(&ran);
+        JOBS.with(|jobs| jobs.borrow_mut().set_timeout(&timed_out, Duration::ZERO));
+        assert_eq!(check(&timed_out), JOB_TIMED_OUT);
+        // Other tests may skip jobs of their own at the same time.
+        assert!(STATS.skipped.load(Ordering::Relaxed) >= skipped + 2);
+
+        drop(release);
+        assert_eq!(wait_for(&blocker), "released");
+        let after = queue(&ran);
+        assert_eq!(wait_for(&after), "ran");
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn check_many_reports_each_job() {
+        let done = start("test", || "done".to_owned());
+        wait_for_settled(&done);
+        let results: HashMap<String, String> =
+            serde_json::from_str(&check_many(&format!("[{}, \"nope\"]", done)).unwrap()).unwrap();
+        assert_eq!(results[&done], "done");
+        assert_eq!(results["nope"], NO_SUCH_JOB);
+        assert_eq!(check(&done), NO_SUCH_JOB);
+    }
+
+    #[test]
+    fn list_shows_outstanding_jobs() {
+        let id = start("test_list", || "listed".to_owned());
+        let list = JOBS.with(|jobs| jobs.borrow().list());
+        let job = list["jobs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|job| job["id"] == id.as_str())
+            .unwrap();
+        assert_eq!(job["queue"], "test_list");
+        assert!(list["started"].as_u64().unwrap() >= 1);
+        assert_eq!(wait_for(&id), "listed");
+    }
+}
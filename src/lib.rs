@@ -8,6 +8,8 @@ mod byond;
 #[allow(dead_code)]
 mod error;
 
+#[cfg(feature = "config")]
+mod config;
 #[cfg(feature = "jobs")]
 mod jobs;
 
@@ -39,6 +41,8 @@ pub mod log;
 pub mod noise_gen;
 #[cfg(feature = "pathfinder")]
 pub mod pathfinder;
+#[cfg(feature = "profile")]
+pub mod profile;
 #[cfg(feature = "random_room_placement")]
 pub mod random_room_placement;
 #[cfg(feature = "redis_pubsub")]
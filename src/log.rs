@@ -1,47 +1,54 @@
-use crate::error::Result;
+use crate::{config, error::Result};
 use chrono::Utc;
 use std::{
-    cell::{Cell, RefCell},
+    cell::RefCell,
     collections::hash_map::{Entry, HashMap},
     ffi::OsString,
     fs,
     fs::{File, OpenOptions},
     io::Write,
     path::Path,
-    sync::Once,
+    sync::Mutex,
     thread::JoinHandle,
 };
 
-static ONCE: Once = Once::new();
+const DEFAULT_TIMESTAMP_FORMAT: &str = "%F %T%.3f";
+
+type Sender = flume::Sender<Box<(String, String, bool)>>;
+
+// Shared so other modules can log from their own threads too.
+static WORKER: Mutex<Option<(Sender, JoinHandle<()>)>> = Mutex::new(None);
 
 thread_local! {
     static FILE_MAP: RefCell<HashMap<OsString, File>> = RefCell::new(HashMap::new()); //on worker thread
-    static SENDER: RefCell<Option<flume::Sender<Box<(String, String, bool)>>>> = RefCell::new(None); //on main thread
-    static HANDLE: Cell<Option<JoinHandle<()>>> = Cell::new(None); //on main thread
 }
 
 byond_fn!(fn log_write(path, data, ...rest) {
-    init_worker();
-    SENDER.with(|sender| {
-        _ = sender.borrow().as_ref().unwrap().send(Box::new(
-            (path.to_string(), data.to_string(), rest.first().map(|x| &**x) == Some("false"))
-        ))
-    });
+    write(path.to_string(), data.to_string(), rest.first().map(|x| &**x) == Some("false"));
     Some("")
 });
 
 byond_fn!(
     fn log_close_all() {
-        SENDER.with(|cell| cell.replace(None));
-        HANDLE.with(|cell| {
-            if let Some(handle) = cell.replace(None) {
-                let _ = handle.join();
-            };
-        });
+        // Holding the lock while the worker finishes up, so nothing can start
+        // another one in the meantime.
+        let mut worker = WORKER.lock().unwrap();
+        if let Some((sender, handle)) = worker.take() {
+            drop(sender);
+            let _ = handle.join();
+        }
         Some("")
     }
 );
 
+/// Queues `data` to be appended to the file at `path`, timestamped unless
+/// `raw`, starting the worker thread if it isn't running.
+pub(crate) fn write(path: String, data: String, raw: bool) {
+    let mut worker = WORKER.lock().unwrap();
+    let (sender, _) = worker.get_or_insert_with(init_worker);
+    _ = sender.send(Box::new((path, data, raw)));
+}
+
 fn open(path: &Path) -> Result<File> {
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent)?
@@ -49,58 +56,54 @@ fn open(path: &Path) -> Result<File> {
 
     Ok(OpenOptions::new().append(true).create(true).open(path)?)
 }
-fn init_worker() {
-    ONCE.call_once(|| {
-        let (sender, receiver) = flume::unbounded();
-        SENDER.with(|cell| *cell.borrow_mut() = Some(sender));
-        HANDLE.with(|cell| {
-            let handle = std::thread::spawn(move || {
-                loop {
-                    let packet = receiver.recv();
+fn init_worker() -> (Sender, JoinHandle<()>) {
+    let (sender, receiver): (Sender, _) = flume::unbounded();
+    let handle = std::thread::spawn(move || {
+        loop {
+            let packet = receiver.recv();
 
-                    if let Ok(packet) = packet {
-                        let (path, data, rest) = *packet;
-                        _ = FILE_MAP.with(|cell| -> Result<()> {
-                            // open file
-                            let mut map = cell.borrow_mut();
-                            let path = Path::new(&path);
-                            let file = match map.entry(path.into()) {
-                                Entry::Occupied(elem) => elem.into_mut(),
-                                Entry::Vacant(elem) => elem.insert(open(path)?),
-                            };
+            if let Ok(packet) = packet {
+                let (path, data, rest) = *packet;
+                _ = FILE_MAP.with(|cell| -> Result<()> {
+                    // open file
+                    let mut map = cell.borrow_mut();
+                    let path = Path::new(&path);
+                    let file = match map.entry(path.into()) {
+                        Entry::Occupied(elem) => elem.into_mut(),
+                        Entry::Vacant(elem) => elem.insert(open(path)?),
+                    };
 
-                            let mut buffer = std::io::BufWriter::new(file);
+                    let mut buffer = std::io::BufWriter::new(file);
 
-                            if rest {
-                                // Write the data to the file with no accoutrements.
-                                write!(buffer, "{}", data)?;
-                            } else {
-                                // write first line, timestamped
-                                let mut iter = data.split('\n');
-                                if let Some(line) = iter.next() {
-                                    write!(
-                                        buffer,
-                                        "[{}] {}\n",
-                                        Utc::now().format("%F %T%.3f"),
-                                        line
-                                    )?;
-                                }
-
-                                // write remaining lines
-                                for line in iter {
-                                    write!(buffer, " - {}\n", line)?;
-                                }
-                            }
-
-                            Ok(())
-                        });
+                    if rest {
+                        // Write the data to the file with no accoutrements.
+                        write!(buffer, "{}", data)?;
                     } else {
-                        FILE_MAP.with(|cell| cell.borrow_mut().clear());
-                        return;
+                        // write first line, timestamped
+                        let mut iter = data.split('\n');
+                        if let Some(line) = iter.next() {
+                            let timestamp = config::read(|c| {
+                                let format = c.log.timestamp_format.as_deref();
+                                Utc::now()
+                                    .format(format.unwrap_or(DEFAULT_TIMESTAMP_FORMAT))
+                                    .to_string()
+                            });
+                            write!(buffer, "[{}] {}\n", timestamp, line)?;
+                        }
+
+                        // write remaining lines
+                        for line in iter {
+                            write!(buffer, " - {}\n", line)?;
+                        }
                     }
-                }
-            });
-            cell.set(Some(handle));
-        });
+
+                    Ok(())
+                });
+            } else {
+                FILE_MAP.with(|cell| cell.borrow_mut().clear());
+                return;
+            }
+        }
     });
+    (sender, handle)
 }
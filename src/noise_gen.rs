@@ -4,7 +4,7 @@ use std::{
     collections::hash_map::{Entry, HashMap},
 };
 
-use crate::error::Result;
+use crate::error::{envelope, Result};
 
 thread_local! {
     static GENERATORS: RefCell<HashMap<String,  Perlin>> = RefCell::new(HashMap::new());
@@ -14,6 +14,10 @@ byond_fn!(fn noise_get_at_coordinates(seed, x, y) {
     get_at_coordinates(seed, x, y).ok()
 });
 
+byond_fn!(fn noise_get_at_coordinates_v2(seed, x, y) {
+    Some(envelope(get_at_coordinates(seed, x, y)))
+});
+
 //note that this will be 0 at integer x & y, scaling is left up to the caller
 fn get_at_coordinates(seed_as_str: &str, x_as_str: &str, y_as_str: &str) -> Result<String> {
     let x = x_as_str.parse::<f64>()?;
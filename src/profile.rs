@@ -0,0 +1,59 @@
+//! Call counts and timings for every exported function.
+use once_cell::sync::Lazy;
+use serde_json::json;
+use std::{cmp::Reverse, collections::HashMap, sync::Mutex, time::Duration};
+
+#[derive(Default)]
+struct Entry {
+    calls: u64,
+    total: Duration,
+    max: Duration,
+}
+
+static ENTRIES: Lazy<Mutex<HashMap<&'static str, Entry>>> = Lazy::new(Default::default);
+
+pub fn record(name: &'static str, elapsed: Duration) {
+    let mut entries = ENTRIES.lock().unwrap();
+    let entry = entries.entry(name).or_default();
+    entry.calls += 1;
+    entry.total += elapsed;
+    entry.max = entry.max.max(elapsed);
+}
+
+// Shaped like BYOND's own profiler output, so it can be fed straight into
+// `influxdb2_publish_profile`. Times are in seconds, and since these calls
+// never call back into DM, self, total and real time are all the same.
+fn dump() -> String {
+    let entries = ENTRIES.lock().unwrap();
+    let mut sorted: Vec<_> = entries.iter().collect();
+    sorted.sort_by_key(|(_, entry)| Reverse(entry.total));
+    let dump: Vec<_> = sorted
+        .into_iter()
+        .map(|(name, entry)| {
+            let total = entry.total.as_secs_f64();
+            json!({
+                "name": name,
+                "self": total,
+                "total": total,
+                "real": total,
+                "over": 0,
+                "calls": entry.calls,
+                "max": entry.max.as_secs_f64(),
+            })
+        })
+        .collect();
+    json!(dump).to_string()
+}
+
+byond_fn!(
+    fn rustg_profile_dump() {
+        Some(dump())
+    }
+);
+
+byond_fn!(
+    fn rustg_profile_reset() {
+        ENTRIES.lock().unwrap().clear();
+        Some("")
+    }
+);
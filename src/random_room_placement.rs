@@ -1,4 +1,4 @@
-use crate::error::Result;
+use crate::error::{envelope, Result};
 use rand::distributions::WeightedIndex;
 use rand::prelude::*;
 use rand::rngs::StdRng;
@@ -14,6 +14,10 @@ byond_fn!(fn random_room_generate(width, height, desired_room_count, hash) {
     random_room_gen(width, height, desired_room_count, hash).ok()
 });
 
+byond_fn!(fn random_room_generate_v2(width, height, desired_room_count, hash) {
+    Some(envelope(random_room_gen(width, height, desired_room_count, hash)))
+});
+
 fn random_room_gen(
     width_as_str: &str,
     height_as_str: &str,
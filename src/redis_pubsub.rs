@@ -1,3 +1,4 @@
+use crate::config;
 use redis::{Client, Commands, RedisError};
 use std::cell::RefCell;
 use std::collections::HashMap;
@@ -5,6 +6,7 @@ use std::thread;
 use std::time::Duration;
 
 const ERROR_CHANNEL: &str = "RUSTG_REDIS_ERROR_CHANNEL";
+const DEFAULT_CHANNEL_CAPACITY: usize = 1000;
 
 thread_local! {
     static REQUEST_SENDER: RefCell<Option<flume::Sender<PubSubRequest>>> = RefCell::new(None);
@@ -82,8 +84,10 @@ fn handle_redis(
 fn connect(addr: &str) -> Result<(), RedisError> {
     let client = redis::Client::open(addr)?;
     let _ = client.get_connection_with_timeout(Duration::from_secs(1))?;
-    let (c_sender, c_receiver) = flume::bounded(1000);
-    let (o_sender, o_receiver) = flume::bounded(1000);
+    let capacity = config::read(|c| c.redis_pubsub.channel_capacity);
+    let capacity = capacity.unwrap_or(DEFAULT_CHANNEL_CAPACITY);
+    let (c_sender, c_receiver) = flume::bounded(capacity);
+    let (o_sender, o_receiver) = flume::bounded(capacity);
     REQUEST_SENDER.with(|cell| cell.replace(Some(c_sender)));
     RESPONSE_RECEIVER.with(|cell| cell.replace(Some(o_receiver)));
     thread::spawn(|| handle_redis(client, c_receiver, o_sender));
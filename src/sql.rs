@@ -1,15 +1,32 @@
-use crate::jobs;
+use crate::error::{envelope, Error, Result};
+use crate::{config, jobs};
 use dashmap::DashMap;
 use mysql::{
     consts::{ColumnFlags, ColumnType::*},
     prelude::Queryable,
-    OptsBuilder, Params, Pool,
+    ClientIdentity, Column, Conn, DriverError, Opts, OptsBuilder, Params, Pool, PooledConn,
+    SslOpts,
 };
-use once_cell::sync::Lazy;
-use serde::Deserialize;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, map::Map, Number};
-use std::sync::atomic::AtomicUsize;
-use std::{error::Error, time::Duration};
+use sha2::{Digest, Sha256};
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Condvar, Mutex,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "sql_postgres")]
+mod postgres;
+#[cfg(feature = "sql_sqlite")]
+mod sqlite;
 
 // ----------------------------------------------------------------------------
 // Interface
@@ -18,20 +35,82 @@ const DEFAULT_PORT: u16 = 3306;
 // The `mysql` crate defaults to 10 and 100 for these, but that is too large.
 const DEFAULT_MIN_THREADS: usize = 1;
 const DEFAULT_MAX_THREADS: usize = 10;
+// How long a transaction may sit unused before it is rolled back.
+const DEFAULT_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
+// How long a cursor may sit unused before it is closed.
+const DEFAULT_CURSOR_TIMEOUT: Duration = Duration::from_secs(60);
+// The job queue cursors' queries run in, limited by default in `jobs`.
+const CURSOR_QUEUE: &str = "sql_cursor";
+// How long a blocking fetch holds up the game waiting for its rows.
+const BLOCKING_FETCH_TIMEOUT: Duration = Duration::from_secs(1);
+// How long to wait after failing to connect, doubling with each failure.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+// How long killing a query waits on the server: to connect, if the pool
+// doesn't say, and for each read and write after that.
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+// `sql_ping` reports the pool as busy rather than wait longer for a connection.
+const PING_CONN_TIMEOUT_MS: u32 = 1000;
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    #[default]
+    Mysql,
+    #[cfg(feature = "sql_postgres")]
+    Postgres,
+    #[cfg(feature = "sql_sqlite")]
+    Sqlite,
+}
 
 #[derive(Deserialize)]
 struct ConnectOptions {
+    #[serde(default)]
+    backend: Backend,
+    // The database file, for SQLite.
+    #[cfg(feature = "sql_sqlite")]
+    path: Option<String>,
     host: Option<String>,
     port: Option<u16>,
     user: Option<String>,
     pass: Option<String>,
     db_name: Option<String>,
+    // A unix socket (or on Windows, a named pipe) to use instead of host and
+    // port. For PostgreSQL, the directory holding the socket.
+    socket_path: Option<String>,
+    // Present to require TLS.
+    ssl: Option<SslOptions>,
+    connect_timeout: Option<f32>,
     read_timeout: Option<f32>,
     write_timeout: Option<f32>,
     min_threads: Option<usize>,
     max_threads: Option<usize>,
 }
 
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SslOptions {
+    // A PEM or DER certificate to trust instead of the system's.
+    ca_file: Option<String>,
+    // A PKCS #12 archive of the client's certificate and key.
+    client_cert: Option<String>,
+    client_cert_pass: Option<String>,
+    verify: SslVerify,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SslVerify {
+    // The certificate and that it is for the host.
+    #[default]
+    Full,
+    // Only the certificate, for servers reached by another name.
+    Ca,
+    // Nothing, so the connection is encrypted but not authenticated.
+    #[serde(rename = "none")]
+    Disabled,
+}
+
 byond_fn!(fn sql_connect_pool(options) {
     let options = match serde_json::from_str::<ConnectOptions>(options) {
         Ok(options) => options,
@@ -43,23 +122,69 @@ byond_fn!(fn sql_connect_pool(options) {
     })
 });
 
-byond_fn!(fn sql_query_blocking(handle, query, params) {
-    Some(match do_query(handle, query, params) {
+// Takes an optional json object of `QueryOptions` after the params.
+byond_fn!(fn sql_query_blocking(handle, query, params, ...rest) {
+    let options = rest.first().map_or("", |o| &**o);
+    Some(match do_query(handle, query, params, options) {
         Ok(o) => o.to_string(),
         Err(e) => err_to_json(e)
     })
 });
 
-byond_fn!(fn sql_query_async(handle, query, params) {
+byond_fn!(fn sql_query_async(handle, query, params, ...rest) {
+    let handle = handle.to_owned();
+    let query = query.to_owned();
+    let params = params.to_owned();
+    let options = rest.first().map_or(String::new(), |o| o.to_string());
+    Some(start_cancellable_query(handle, query, params, options, |result| match result {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    }))
+});
+
+byond_fn!(fn sql_connect_pool_v2(options) {
+    Some(envelope(
+        serde_json::from_str::<ConnectOptions>(options)
+            .map_err(Error::from)
+            .and_then(sql_connect),
+    ))
+});
+
+byond_fn!(fn sql_query_blocking_v2(handle, query, params, ...rest) {
+    let options = rest.first().map_or("", |o| &**o);
+    Some(envelope(do_query(handle, query, params, options)))
+});
+
+byond_fn!(fn sql_query_async_v2(handle, query, params, ...rest) {
+    let handle = handle.to_owned();
+    let query = query.to_owned();
+    let params = params.to_owned();
+    let options = rest.first().map_or(String::new(), |o| o.to_string());
+    Some(start_cancellable_query(handle, query, params, options, envelope))
+});
+
+// Runs a query and writes its rows to a CSV or NDJSON file at `path`, without
+// passing them through BYOND. Takes an optional json object of
+// `ExportOptions` after the path.
+byond_fn!(fn sql_export_async(handle, query, params, path, ...rest) {
     let handle = handle.to_owned();
     let query = query.to_owned();
     let params = params.to_owned();
-    Some(jobs::start(move || {
-        match do_query(&handle, &query, &params) {
+    let path = path.to_owned();
+    let options = rest.first().map_or(String::new(), |o| o.to_string());
+    Some(start_cancellable(
+        move |control| export_query(&handle, &query, &params, &path, &options, control),
+        |result| match result {
             Ok(o) => o.to_string(),
             Err(e) => err_to_json(e)
-        }
-    }))
+        },
+    ))
+});
+
+// Kills a query started by `sql_query_async`, `sql_query_async_v2` or
+// `sql_export_async`, which then finishes with a cancelled error.
+byond_fn!(fn sql_cancel_query(job_id) {
+    Some(cancel_query(job_id).to_string())
 });
 
 // hopefully won't panic if queries are running
@@ -69,13 +194,12 @@ byond_fn!(fn sql_disconnect_pool(handle) {
         Err(e) => return Some(err_to_json(e)),
     };
     Some(
-         match POOL.remove(&handle) {
-            Some(_) => {
-                json!({
-                    "status": "success"
-                }).to_string()
-            },
-            None => json!({
+         if POOL.remove(&handle).is_some() || DATABASES.remove(&handle).is_some() {
+            json!({
+                "status": "success"
+            }).to_string()
+        } else {
+            json!({
                 "status": "offline"
             }).to_string()
         }
@@ -88,127 +212,576 @@ byond_fn!(fn sql_connected(handle) {
         Err(e) => return Some(err_to_json(e)),
     };
     Some(
-        match POOL.get(&handle) {
-            Some(_) => json!({
+        if POOL.contains_key(&handle) || DATABASES.contains_key(&handle) {
+            json!({
                 "status": "online"
-            }).to_string(),
-            None => json!({
+            }).to_string()
+        } else {
+            json!({
                 "status": "offline"
             }).to_string()
         }
     )
 });
 
+// Checks the database can actually be reached, where `sql_connected` only
+// checks the handle is valid. Gives `online` with the round trip `latency` in
+// milliseconds, `busy` if every connection is in use, or `unreachable`.
+byond_fn!(fn sql_ping_blocking(handle) {
+    Some(match ping(handle) {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    })
+});
+
+byond_fn!(fn sql_ping_async(handle) {
+    let handle = handle.to_owned();
+    Some(jobs::start("sql", move || {
+        match ping(&handle) {
+            Ok(o) => o.to_string(),
+            Err(e) => err_to_json(e)
+        }
+    }))
+});
+
+byond_fn!(fn sql_pool_stats(handle) {
+    Some(match pool_stats(handle) {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    })
+});
+
+// Up to `count` of the slowest queries run since the library was loaded or
+// `sql_clear_slowest_queries` was last called, slowest first.
+byond_fn!(fn sql_slowest_queries(count) {
+    Some(match slowest_queries(count) {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    })
+});
+
+byond_fn!(
+    fn sql_clear_slowest_queries() {
+        SLOWEST.lock().unwrap().clear();
+        Some("")
+    }
+);
+
 byond_fn!(fn sql_check_query(id) {
     Some(jobs::check(id))
 });
 
+// Runs one query for each set of params in a json array, all on a single
+// connection. Takes an optional json object of `BatchOptions`. Failing rows
+// are reported by index in `errors` rather than failing the whole batch.
+byond_fn!(fn sql_query_batch_blocking(handle, query, params_list, ...rest) {
+    let options = rest.first().map_or("", |o| &**o);
+    Some(match do_batch(handle, query, params_list, options) {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    })
+});
+
+byond_fn!(fn sql_query_batch_async(handle, query, params_list, ...rest) {
+    let handle = handle.to_owned();
+    let query = query.to_owned();
+    let params_list = params_list.to_owned();
+    let options = rest.first().map_or(String::new(), |o| o.to_string());
+    Some(jobs::start("sql", move || {
+        match do_batch(&handle, &query, &params_list, &options) {
+            Ok(o) => o.to_string(),
+            Err(e) => err_to_json(e)
+        }
+    }))
+});
+
+// Applies the `<version>_<description>.sql` files in `dir` which haven't been
+// yet, in version order, recording each in `rustg_schema_migrations`. Refuses
+// to run anything if an applied file has since changed.
+byond_fn!(fn sql_migrate_blocking(handle, dir) {
+    Some(match migrate(handle, dir) {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    })
+});
+
+byond_fn!(fn sql_migrate_async(handle, dir) {
+    let handle = handle.to_owned();
+    let dir = dir.to_owned();
+    Some(jobs::start("sql", move || {
+        match migrate(&handle, &dir) {
+            Ok(o) => o.to_string(),
+            Err(e) => err_to_json(e)
+        }
+    }))
+});
+
+// Takes a connection from the pool and starts a transaction on it. The handle
+// returned can be passed to the query functions in place of the pool handle,
+// and must be ended with `sql_commit` or `sql_rollback`. If it goes unused for
+// the timeout (in seconds, 60 by default) it is rolled back.
+byond_fn!(fn sql_begin(handle, ...rest) {
+    let timeout = match rest.first().filter(|s| !s.is_empty()) {
+        Some(seconds) => match seconds.parse::<f32>() {
+            Ok(seconds) => match Duration::try_from_secs_f32(seconds) {
+                Ok(timeout) => Some(timeout),
+                Err(e) => return Some(err_to_json(e)),
+            },
+            Err(e) => return Some(err_to_json(e)),
+        },
+        None => None,
+    };
+    Some(match begin(handle, timeout) {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    })
+});
+
+byond_fn!(fn sql_commit(handle) {
+    Some(match end_transaction(handle, "COMMIT") {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    })
+});
+
+byond_fn!(fn sql_rollback(handle) {
+    Some(match end_transaction(handle, "ROLLBACK") {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    })
+});
+
+// Starts running a query whose rows are read a batch at a time with
+// `sql_cursor_fetch_*`, for results too big to return at once. Takes the same
+// arguments as `sql_query_blocking`.
+byond_fn!(fn sql_cursor_open(handle, query, params, ...rest) {
+    let options = rest.first().map_or("", |o| &**o);
+    Some(match open_cursor(handle, query, params, options) {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    })
+});
+
+byond_fn!(fn sql_cursor_fetch_blocking(cursor, count) {
+    Some(match fetch_cursor(cursor, count, BLOCKING_FETCH_TIMEOUT) {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    })
+});
+
+byond_fn!(fn sql_cursor_fetch_async(cursor, count) {
+    let cursor = cursor.to_owned();
+    let count = count.to_owned();
+    Some(jobs::start("sql", move || {
+        match fetch_cursor(&cursor, &count, cursor_timeout()) {
+            Ok(o) => o.to_string(),
+            Err(e) => err_to_json(e)
+        }
+    }))
+});
+
+byond_fn!(fn sql_cursor_close(cursor) {
+    Some(match close_cursor(cursor) {
+        Ok(o) => o.to_string(),
+        Err(e) => err_to_json(e)
+    })
+});
+
 // ----------------------------------------------------------------------------
 // Main connect and query implementation
 
-static POOL: Lazy<DashMap<usize, Pool>> = Lazy::new(DashMap::new);
+static POOL: Lazy<DashMap<usize, Arc<ConnPool>>> = Lazy::new(DashMap::new);
 static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
 
-fn sql_connect(options: ConnectOptions) -> Result<serde_json::Value, Box<dyn Error>> {
+struct ConnPool {
+    pool: Pool,
+    // For connections made outside the pool, to kill queries with.
+    opts: Arc<Opts>,
+    min_threads: usize,
+    max_threads: usize,
+    stats: Arc<PoolStats>,
+}
+
+#[derive(Default)]
+struct PoolStats {
+    // Connections handed out and not yet given back, including those held by
+    // transactions and cursors.
+    active: AtomicUsize,
+    // Callers blocked waiting for a connection.
+    waiting: AtomicUsize,
+    queries: AtomicUsize,
+    errors: AtomicUsize,
+    backoff: Mutex<Backoff>,
+}
+
+// After failing to connect, the pool refuses further attempts for a while,
+// doubling each time, rather than every queued query waiting out its own
+// connection timeout against a server that is down.
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl PoolStats {
+    fn record(&self, ok: bool) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn retry_in(&self) -> Option<Duration> {
+        let backoff = self.backoff.lock().unwrap();
+        let wait = backoff.retry_at?.checked_duration_since(Instant::now())?;
+        Some(wait).filter(|wait| !wait.is_zero())
+    }
+
+    fn connected(&self) {
+        *self.backoff.lock().unwrap() = Backoff::default();
+    }
+
+    fn failed_to_connect(&self) {
+        let mut backoff = self.backoff.lock().unwrap();
+        let wait = RECONNECT_BACKOFF_MIN
+            .saturating_mul(2u32.saturating_pow(backoff.failures))
+            .min(RECONNECT_BACKOFF_MAX);
+        backoff.failures += 1;
+        backoff.retry_at = Some(Instant::now() + wait);
+    }
+}
+
+impl ConnPool {
+    fn get_conn(&self) -> Result<Checkout> {
+        if let Some(wait) = self.stats.retry_in() {
+            return Err(Error::SqlReconnecting(wait.as_secs_f32()));
+        }
+        self.checkout(self.pool.get_conn())
+    }
+
+    // Tracks a connection taken from the pool by whatever means.
+    fn checkout(&self, conn: mysql::Result<PooledConn>) -> Result<Checkout> {
+        match conn {
+            Ok(conn) => {
+                self.stats.connected();
+                self.stats.active.fetch_add(1, Ordering::Relaxed);
+                Ok(Checkout {
+                    conn,
+                    stats: self.stats.clone(),
+                    opts: self.opts.clone(),
+                })
+            }
+            Err(e) => {
+                // Running out of connections doesn't mean the server is gone.
+                if !matches!(e, mysql::Error::DriverError(DriverError::Timeout)) {
+                    self.stats.failed_to_connect();
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    fn blocking_checkout(&self) -> Result<Checkout> {
+        self.stats.waiting.fetch_add(1, Ordering::Relaxed);
+        let conn = self.get_conn();
+        self.stats.waiting.fetch_sub(1, Ordering::Relaxed);
+        conn
+    }
+}
+
+// Errors for handles of other backends, which don't support what the pool is
+// wanted for.
+fn get_pool(handle: usize) -> Result<Option<Arc<ConnPool>>> {
+    if DATABASES.contains_key(&handle) {
+        return Err(Error::SqlUnsupported);
+    }
+    Ok(POOL.get(&handle).map(|pool| pool.clone()))
+}
+
+// A pooled connection, counted as active until it is dropped.
+struct Checkout {
+    conn: PooledConn,
+    stats: Arc<PoolStats>,
+    opts: Arc<Opts>,
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl std::ops::Deref for Checkout {
+    type Target = PooledConn;
+
+    fn deref(&self) -> &PooledConn {
+        &self.conn
+    }
+}
+
+impl std::ops::DerefMut for Checkout {
+    fn deref_mut(&mut self) -> &mut PooledConn {
+        &mut self.conn
+    }
+}
+
+fn ping(handle: &str) -> Result<serde_json::Value> {
+    let handle = handle.parse()?;
+    if let Some(database) = get_database(handle) {
+        return database.ping();
+    }
+    let pool = match get_pool(handle)? {
+        Some(pool) => pool,
+        None => return Ok(json!({"status": "offline"})),
+    };
+    // Skips the backoff: this is how DM finds out the server is back.
+    let started = Instant::now();
+    let mut conn = match pool.checkout(pool.pool.try_get_conn(PING_CONN_TIMEOUT_MS)) {
+        Ok(conn) => conn,
+        Err(Error::Sql(mysql::Error::DriverError(DriverError::Timeout))) => {
+            return Ok(json!({"status": "busy"}))
+        }
+        Err(e) => return Ok(json!({"status": "unreachable", "data": e.to_string()})),
+    };
+    if !conn.as_mut().ping() {
+        return Ok(json!({"status": "unreachable", "data": "No reply to ping."}));
+    }
+    Ok(json!({
+        "status": "online",
+        "latency": started.elapsed().as_secs_f64() * 1000.0,
+    }))
+}
+
+fn pool_stats(handle: &str) -> Result<serde_json::Value> {
+    let pool = match get_pool(handle.parse()?)? {
+        Some(pool) => pool,
+        None => return Ok(json!({"status": "offline"})),
+    };
+    let stats = &pool.stats;
+    let failures = stats.backoff.lock().unwrap().failures;
+    Ok(json!({
+        "status": "ok",
+        // `mysql` opens and closes connections without saying, so how many
+        // are open isn't known.
+        "open": null,
+        "active": stats.active.load(Ordering::Relaxed),
+        "idle": null,
+        "waiting": stats.waiting.load(Ordering::Relaxed),
+        "min_threads": pool.min_threads,
+        "max_threads": pool.max_threads,
+        "queries": stats.queries.load(Ordering::Relaxed),
+        "errors": stats.errors.load(Ordering::Relaxed),
+        "connect_failures": failures,
+        "retry_in": stats.retry_in().map(|wait| wait.as_secs_f32()),
+    }))
+}
+
+fn sql_connect(options: ConnectOptions) -> Result<serde_json::Value> {
+    let handle = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    match options.backend {
+        Backend::Mysql => {
+            POOL.insert(handle, Arc::new(connect_mysql(options)?));
+        }
+        #[cfg(feature = "sql_postgres")]
+        Backend::Postgres => {
+            let database = postgres::Database::open(&options)?;
+            DATABASES.insert(handle, Arc::new(Database::Postgres(Box::new(database))));
+        }
+        #[cfg(feature = "sql_sqlite")]
+        Backend::Sqlite => {
+            let database = sqlite::Database::open(&options)?;
+            DATABASES.insert(handle, Arc::new(Database::Sqlite(database)));
+        }
+    }
+    Ok(json!({
+        "status": "ok",
+        "handle": handle.to_string(),
+    }))
+}
+
+// The connection limits asked for, or failing that configured.
+fn thread_limits(options: &ConnectOptions) -> (usize, usize) {
+    let min_threads = options
+        .min_threads
+        .unwrap_or_else(|| config::read(|c| c.sql.min_threads).unwrap_or(DEFAULT_MIN_THREADS));
+    let max_threads = options
+        .max_threads
+        .unwrap_or_else(|| config::read(|c| c.sql.max_threads).unwrap_or(DEFAULT_MAX_THREADS));
+    (min_threads, max_threads)
+}
+
+fn connect_mysql(options: ConnectOptions) -> Result<ConnPool> {
+    let (min_threads, max_threads) = thread_limits(&options);
+    let connect_timeout = parse_seconds("connect_timeout", options.connect_timeout)?;
+    let ssl_opts = options.ssl.as_ref().map(mysql_ssl_opts);
     let builder = OptsBuilder::new()
         .ip_or_hostname(options.host)
         .tcp_port(options.port.unwrap_or(DEFAULT_PORT))
         // Work around addresses like `localhost:3307` defaulting to socket as
-        // if the port were the default too.
-        .prefer_socket(options.port.map_or(true, |p| p == DEFAULT_PORT))
+        // if the port were the default too. Asking for TLS or a particular
+        // socket means sticking to what was asked for.
+        .prefer_socket(
+            options.ssl.is_none()
+                && options.socket_path.is_none()
+                && options.port.is_none_or(|p| p == DEFAULT_PORT),
+        )
+        .socket(options.socket_path)
+        .ssl_opts(ssl_opts)
+        .tcp_connect_timeout(connect_timeout)
         .user(options.user)
         .pass(options.pass)
         .db_name(options.db_name)
         .read_timeout(options.read_timeout.map(Duration::from_secs_f32))
         .write_timeout(options.write_timeout.map(Duration::from_secs_f32));
+    let opts = Opts::from(builder);
+    let pool = Pool::new_manual(min_threads, max_threads, opts.clone())?;
+    Ok(ConnPool {
+        pool,
+        opts: Arc::new(opts),
+        min_threads,
+        max_threads,
+        stats: Default::default(),
+    })
+}
 
-    let pool = Pool::new_manual(
-        options.min_threads.unwrap_or(DEFAULT_MIN_THREADS),
-        options.max_threads.unwrap_or(DEFAULT_MAX_THREADS),
-        builder,
-    )?;
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct QueryOptions {
+    // Rows as objects keyed by column name, rather than arrays.
+    rows_as_objects: bool,
+    // Seconds before the query is killed.
+    timeout: Option<f32>,
+}
 
-    let handle = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-    POOL.insert(handle, pool);
-    Ok(json!({
-        "status": "ok",
-        "handle": handle.to_string(),
-    }))
+fn parse_query_options(options: &str) -> Result<QueryOptions> {
+    Ok(if options.is_empty() {
+        QueryOptions::default()
+    } else {
+        serde_json::from_str(options)?
+    })
+}
+
+fn do_query(handle: &str, query: &str, params: &str, options: &str) -> Result<serde_json::Value> {
+    do_controlled_query(handle, query, params, options, &QueryControl::default())
+}
+
+fn do_controlled_query(
+    handle: &str,
+    query: &str,
+    params: &str,
+    options: &str,
+    control: &QueryControl,
+) -> Result<serde_json::Value> {
+    let started = Instant::now();
+    let result = query_handle(handle, query, params, options, control);
+    record_query(query, params, started.elapsed(), &result);
+    result
+}
+
+fn query_handle(
+    handle: &str,
+    query: &str,
+    params: &str,
+    options: &str,
+    control: &QueryControl,
+) -> Result<serde_json::Value> {
+    let options = parse_query_options(options)?;
+    let timeout = parse_seconds("timeout", options.timeout)?;
+    let handle = handle.parse()?;
+    if let Some(database) = get_database(handle) {
+        return database.query(query, params, &options, control, timeout);
+    }
+    let result = with_conn(handle, |conn| {
+        let killer = Killer::Mysql(conn.as_ref().connection_id(), conn.opts.clone());
+        control.run(killer, timeout, || run_query(conn, query, params, &options))
+    })?;
+    Ok(result.unwrap_or_else(|| json!({"status": "offline"})))
 }
 
-fn do_query(handle: &str, query: &str, params: &str) -> Result<serde_json::Value, Box<dyn Error>> {
-    let mut conn = {
-        let pool = match POOL.get(&handle.parse()?) {
-            Some(s) => s,
-            None => return Ok(json!({"status": "offline"})),
+fn parse_seconds(name: &str, seconds: Option<f32>) -> Result<Option<Duration>> {
+    seconds
+        .map(|seconds| {
+            Duration::try_from_secs_f32(seconds)
+                .map_err(|e| Error::SqlParam(format!("{}: {}", name, e)))
+        })
+        .transpose()
+}
+
+fn mysql_ssl_opts(ssl: &SslOptions) -> SslOpts {
+    let identity = ssl.client_cert.as_ref().map(|path| {
+        let identity = ClientIdentity::new(PathBuf::from(path));
+        match &ssl.client_cert_pass {
+            Some(pass) => identity.with_password(pass.clone()),
+            None => identity,
+        }
+    });
+    SslOpts::default()
+        .with_root_cert_path(ssl.ca_file.as_ref().map(PathBuf::from))
+        .with_client_identity(identity)
+        .with_danger_skip_domain_validation(!matches!(ssl.verify, SslVerify::Full))
+        .with_danger_accept_invalid_certs(matches!(ssl.verify, SslVerify::Disabled))
+}
+
+// Runs `f` on the connection of a transaction, or one from a pool, depending
+// on what `handle` is. `None` if it is neither.
+fn with_conn<T>(handle: usize, f: impl FnOnce(&mut Checkout) -> Result<T>) -> Result<Option<T>> {
+    // Clone the transaction out so the map isn't locked for the whole query.
+    let transaction = TRANSACTIONS.get(&handle).map(|t| t.clone());
+    if let Some(transaction) = transaction {
+        let mut transaction = transaction.lock().unwrap();
+        let result = match transaction.conn.as_mut() {
+            Some(conn) => {
+                let result = f(conn);
+                conn.stats.record(result.is_ok());
+                result.map(Some)
+            }
+            // It was ended while this query waited for it.
+            None => Ok(None),
         };
-        pool.get_conn()?
+        transaction.last_used = Instant::now();
+        return result;
+    }
+
+    let pool = match get_pool(handle)? {
+        Some(pool) => pool,
+        None => return Ok(None),
     };
+    let mut conn = match pool.blocking_checkout() {
+        Ok(conn) => conn,
+        Err(e) => {
+            pool.stats.record(false);
+            return Err(e);
+        }
+    };
+    let result = f(&mut conn);
+    conn.stats.record(result.is_ok());
+    result.map(Some)
+}
 
-    let query_result = conn.exec_iter(query, params_from_json(params))?;
+fn run_query(
+    conn: &mut PooledConn,
+    query: &str,
+    params: &str,
+    options: &QueryOptions,
+) -> Result<serde_json::Value> {
+    let query_result = conn.exec_iter(query, params_from_json(params)?)?;
     let affected = query_result.affected_rows();
     let last_insert_id = query_result.last_insert_id();
-    let mut columns = Vec::new();
-    for col in query_result.columns().as_ref().iter() {
-        columns.push(json! {{
-            "name": col.name_str(),
-            // Expansion room left for other column metadata.
-        }});
-    }
+    let columns: Vec<_> = query_result
+        .columns()
+        .as_ref()
+        .iter()
+        .map(column_to_json)
+        .collect();
+    let keys = options
+        .rows_as_objects
+        .then(|| object_keys(query_result.columns().as_ref()));
 
     let mut rows: Vec<serde_json::Value> = Vec::new();
     for row in query_result {
-        let row = row?;
-        let mut json_row: Vec<serde_json::Value> = Vec::new();
-        for (i, col) in row.columns_ref().iter().enumerate() {
-            let ctype = col.column_type();
-            let value = row
-                .as_ref(i)
-                .ok_or("length of row was smaller than column count")?;
-            let converted = match value {
-                mysql::Value::Bytes(b) => match ctype {
-                    MYSQL_TYPE_VARCHAR | MYSQL_TYPE_STRING | MYSQL_TYPE_VAR_STRING => {
-                        serde_json::Value::String(String::from_utf8_lossy(b).into_owned())
-                    }
-                    MYSQL_TYPE_BLOB
-                    | MYSQL_TYPE_LONG_BLOB
-                    | MYSQL_TYPE_MEDIUM_BLOB
-                    | MYSQL_TYPE_TINY_BLOB => {
-                        if col.flags().contains(ColumnFlags::BINARY_FLAG) {
-                            serde_json::Value::Array(
-                                b.iter()
-                                    .map(|x| serde_json::Value::Number(Number::from(*x)))
-                                    .collect(),
-                            )
-                        } else {
-                            serde_json::Value::String(String::from_utf8_lossy(b).into_owned())
-                        }
-                    }
-                    _ => serde_json::Value::Null,
-                },
-                mysql::Value::Float(f) => serde_json::Value::Number(
-                    Number::from_f64(f64::from(*f)).unwrap_or_else(|| Number::from(0)),
-                ),
-                mysql::Value::Double(f) => serde_json::Value::Number(
-                    Number::from_f64(*f).unwrap_or_else(|| Number::from(0)),
-                ),
-                mysql::Value::Int(i) => serde_json::Value::Number(Number::from(*i)),
-                mysql::Value::UInt(u) => serde_json::Value::Number(Number::from(*u)),
-                mysql::Value::Date(year, month, day, hour, minute, second, _ms) => {
-                    serde_json::Value::String(format!(
-                        "{}-{:02}-{:02} {:02}:{:02}:{:02}",
-                        year, month, day, hour, minute, second
-                    ))
-                }
-                _ => serde_json::Value::Null,
-            };
-            json_row.push(converted)
-        }
-        rows.push(serde_json::Value::Array(json_row));
+        rows.push(row_to_json(row?, keys.as_deref())?);
     }
 
-    drop(conn);
-
     Ok(json! {{
         "status": "ok",
         "affected": affected,
@@ -218,72 +791,1746 @@ fn do_query(handle: &str, query: &str, params: &str) -> Result<serde_json::Value
     }})
 }
 
-// ----------------------------------------------------------------------------
-// Helpers
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct BatchOptions {
+    // All or nothing: the first failing row rolls back the rest.
+    transaction: bool,
+}
 
-fn err_to_json<E: std::fmt::Display>(e: E) -> String {
-    json!({
-        "status": "err",
-        "data": &e.to_string()
-    })
-    .to_string()
+fn do_batch(
+    handle: &str,
+    query: &str,
+    params_list: &str,
+    options: &str,
+) -> Result<serde_json::Value> {
+    let started = Instant::now();
+    let result = run_batch(handle, query, params_list, options);
+    record_query(query, params_list, started.elapsed(), &result);
+    result
 }
 
-fn json_to_mysql(val: serde_json::Value) -> mysql::Value {
-    match val {
-        serde_json::Value::Bool(b) => mysql::Value::UInt(b as u64),
-        serde_json::Value::Number(i) => {
-            if let Some(v) = i.as_u64() {
-                mysql::Value::UInt(v)
-            } else if let Some(v) = i.as_i64() {
-                mysql::Value::Int(v)
-            } else if let Some(v) = i.as_f64() {
-                mysql::Value::Float(v as f32) // Loses precision.
-            } else {
-                mysql::Value::NULL
-            }
+fn run_batch(
+    handle: &str,
+    query: &str,
+    params_list: &str,
+    options: &str,
+) -> Result<serde_json::Value> {
+    let options: BatchOptions = if options.is_empty() {
+        BatchOptions::default()
+    } else {
+        serde_json::from_str(options)?
+    };
+    let params_list: Vec<serde_json::Value> = serde_json::from_str(params_list)?;
+    let handle = handle.parse()?;
+    // Starting a transaction inside one would commit it, so a batch run on a
+    // transaction handle just becomes part of that transaction.
+    let transaction = options.transaction && !TRANSACTIONS.contains_key(&handle);
+    let result = with_conn(handle, |conn| {
+        let stmt = conn.prep(query)?;
+        if transaction {
+            conn.query_drop("START TRANSACTION")?;
         }
-        serde_json::Value::String(s) => mysql::Value::Bytes(s.into()),
-        serde_json::Value::Array(a) => mysql::Value::Bytes(
-            a.into_iter()
-                .map(|x| {
-                    if let serde_json::Value::Number(n) = x {
-                        n.as_u64().unwrap_or(0) as u8
-                    } else {
-                        0
+        let mut affected = 0;
+        let mut errors = Vec::new();
+        for (index, params) in params_list.into_iter().enumerate() {
+            let result =
+                params_from_value(params).and_then(|params| Ok(conn.exec_drop(&stmt, params)?));
+            match result {
+                Ok(()) => affected += conn.affected_rows(),
+                Err(e) => {
+                    errors.push(json!({"index": index, "error": e.to_string()}));
+                    if transaction {
+                        break;
                     }
-                })
-                .collect(),
+                }
+            }
+        }
+        let rolled_back = transaction && !errors.is_empty();
+        if transaction {
+            conn.query_drop(if rolled_back { "ROLLBACK" } else { "COMMIT" })?;
+        }
+        Ok(json!({
+            "status": "ok",
+            "affected": if rolled_back { 0 } else { affected },
+            "errors": errors,
+            "rolled_back": rolled_back,
+        }))
+    })?;
+    Ok(result.unwrap_or_else(|| json!({"status": "offline"})))
+}
+
+fn row_to_json(row: mysql::Row, keys: Option<&[String]>) -> Result<serde_json::Value> {
+    let mut json_row: Vec<serde_json::Value> = Vec::new();
+    for (i, col) in row.columns_ref().iter().enumerate() {
+        let value = row.as_ref(i).ok_or(Error::SqlRowLength)?;
+        json_row.push(mysql_to_json(col, value));
+    }
+    Ok(match keys {
+        Some(keys) => serde_json::Value::Object(keys.iter().cloned().zip(json_row).collect()),
+        None => serde_json::Value::Array(json_row),
+    })
+}
+
+// ----------------------------------------------------------------------------
+// Timeouts and cancellation
+
+// Lets a query be stopped from another thread, by killing it server side.
+#[derive(Clone, Default)]
+struct QueryControl {
+    state: Arc<Mutex<ControlState>>,
+    killed: Arc<Condvar>,
+}
+
+#[derive(Default)]
+struct ControlState {
+    running: Option<Killer>,
+    stopped: Option<StopReason>,
+    // A kill is under way, so the connection mustn't be reused yet.
+    killing: bool,
+    finished: bool,
+}
+
+// Stops whatever is running on a connection.
+enum Killer {
+    // The server's id for the connection, and how to reach the server.
+    Mysql(u32, Arc<Opts>),
+    #[cfg(feature = "sql_postgres")]
+    Postgres(
+        ::postgres::CancelToken,
+        Option<postgres_native_tls::MakeTlsConnector>,
+    ),
+    #[cfg(feature = "sql_sqlite")]
+    Sqlite(rusqlite::InterruptHandle),
+}
+
+impl Killer {
+    fn kill(&self) {
+        match self {
+            Killer::Mysql(id, opts) => {
+                // Holds up the query being killed, so it mustn't wait long on
+                // a server which has gone away.
+                let connect_timeout = opts.get_tcp_connect_timeout().or(Some(KILL_TIMEOUT));
+                let opts = OptsBuilder::from_opts(Opts::clone(opts))
+                    .tcp_connect_timeout(connect_timeout)
+                    .read_timeout(Some(KILL_TIMEOUT))
+                    .write_timeout(Some(KILL_TIMEOUT));
+                let _ = Conn::new(opts)
+                    .and_then(|mut conn| conn.query_drop(format!("KILL QUERY {}", id)));
+            }
+            #[cfg(feature = "sql_postgres")]
+            Killer::Postgres(token, tls) => {
+                let _ = match tls {
+                    Some(tls) => token.cancel_query(tls.clone()),
+                    None => token.cancel_query(::postgres::NoTls),
+                };
+            }
+            #[cfg(feature = "sql_sqlite")]
+            Killer::Sqlite(handle) => handle.interrupt(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum StopReason {
+    TimedOut(Duration),
+    Cancelled,
+}
+
+impl QueryControl {
+    // Runs `f`, which is stopped with `killer` if it outlasts `timeout`.
+    fn run<T>(
+        &self,
+        killer: Killer,
+        timeout: Option<Duration>,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        if !self.start(killer) {
+            return Err(Error::SqlCancelled);
+        }
+        if let Some(timeout) = timeout {
+            // Does nothing if the query has finished by then.
+            let control = self.clone();
+            schedule(timeout, move || control.stop(StopReason::TimedOut(timeout)));
+        }
+        let result = f();
+        match self.finish() {
+            Some(StopReason::TimedOut(timeout)) => Err(Error::SqlTimedOut(timeout.as_secs_f32())),
+            Some(StopReason::Cancelled) => Err(Error::SqlCancelled),
+            None => result,
+        }
+    }
+
+    // False if the query was stopped before it could start.
+    fn start(&self, killer: Killer) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.running = Some(killer);
+        state.stopped.is_none()
+    }
+
+    // Quick enough for the timer thread, as killing talks to the server on a
+    // thread of its own.
+    fn stop(&self, reason: StopReason) {
+        let killer = {
+            let mut state = self.state.lock().unwrap();
+            if state.stopped.is_some() || state.finished {
+                return;
+            }
+            state.stopped = Some(reason);
+            let killer = state.running.take();
+            state.killing = killer.is_some();
+            killer
+        };
+        if let Some(killer) = killer {
+            let control = self.clone();
+            thread::spawn(move || {
+                // `finish` waits on this, so it has to be cleared regardless.
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| killer.kill()));
+                control.state.lock().unwrap().killing = false;
+                control.killed.notify_all();
+            });
+        }
+    }
+
+    // Waits out any kill in progress, so the connection can't be handed back
+    // and have some other query killed in place of this one.
+    fn finish(&self) -> Option<StopReason> {
+        let mut state = self.state.lock().unwrap();
+        while state.killing {
+            state = self.killed.wait(state).unwrap();
+        }
+        state.running = None;
+        state.stopped
+    }
+}
+
+// Async queries which can still be cancelled, by job id.
+static CANCELLABLE: Lazy<DashMap<String, QueryControl>> = Lazy::new(DashMap::new);
+
+fn start_cancellable_query(
+    handle: String,
+    query: String,
+    params: String,
+    options: String,
+    finish: fn(Result<serde_json::Value>) -> String,
+) -> String {
+    start_cancellable(
+        move |control| do_controlled_query(&handle, &query, &params, &options, control),
+        finish,
+    )
+}
+
+// Runs `run` as an `sql` job which `sql_cancel_query` can stop.
+fn start_cancellable(
+    run: impl FnOnce(&QueryControl) -> Result<serde_json::Value> + Send + 'static,
+    finish: fn(Result<serde_json::Value>) -> String,
+) -> String {
+    let control = QueryControl::default();
+    let job_control = control.clone();
+    let job_id = Arc::new(OnceCell::<String>::new());
+    let job_id_inner = job_id.clone();
+    let id = jobs::start("sql", move || {
+        let result = run(&job_control);
+        job_control.state.lock().unwrap().finished = true;
+        if let Some(id) = job_id_inner.get() {
+            CANCELLABLE.remove(id);
+        }
+        finish(result)
+    });
+    let _ = job_id.set(id.clone());
+    CANCELLABLE.insert(id.clone(), control.clone());
+    // Finished before it knew its id, so it couldn't remove itself.
+    if control.state.lock().unwrap().finished {
+        CANCELLABLE.remove(&id);
+    }
+    id
+}
+
+fn cancel_query(job_id: &str) -> serde_json::Value {
+    match CANCELLABLE.remove(job_id) {
+        Some((_, control)) => {
+            control.stop(StopReason::Cancelled);
+            json!({"status": "ok"})
+        }
+        None => json!({"status": "offline"}),
+    }
+}
+
+// ----------------------------------------------------------------------------
+// Timers
+
+// Work to do after a while, all run in turn on one thread: timing out queries,
+// and rolling back abandoned transactions. Tasks hold up the ones after them,
+// so they have to be quick.
+struct Timer {
+    at: Instant,
+    seq: u64,
+    task: Box<dyn FnOnce() + Send>,
+}
+
+// Soonest first in a `BinaryHeap`, ties in the order they were scheduled.
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Timer {}
+
+#[derive(Default)]
+struct Timers {
+    pending: BinaryHeap<Timer>,
+    next_seq: u64,
+}
+
+static TIMERS: Lazy<(Mutex<Timers>, Condvar)> = Lazy::new(|| {
+    thread::spawn(run_timers);
+    Default::default()
+});
+
+fn schedule(after: Duration, task: impl FnOnce() + Send + 'static) {
+    let (timers, changed) = &*TIMERS;
+    let mut timers = timers.lock().unwrap();
+    let seq = timers.next_seq;
+    timers.next_seq += 1;
+    timers.pending.push(Timer {
+        at: Instant::now() + after,
+        seq,
+        task: Box::new(task),
+    });
+    changed.notify_one();
+}
+
+fn run_timers() {
+    let (timers, changed) = &*TIMERS;
+    let mut pending = timers.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        match pending.pending.peek() {
+            Some(timer) if timer.at <= now => {
+                let timer = pending.pending.pop().unwrap();
+                drop(pending);
+                // One bad task mustn't stop the others from ever running.
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(timer.task));
+                pending = timers.lock().unwrap();
+            }
+            Some(timer) => {
+                let wait = timer.at - now;
+                pending = changed.wait_timeout(pending, wait).unwrap().0;
+            }
+            None => pending = changed.wait(pending).unwrap(),
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+// Exports
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ExportOptions {
+    // Taken from the file extension if not given.
+    format: Option<ExportFormat>,
+    timeout: Option<f32>,
+}
+
+fn export_query(
+    handle: &str,
+    query: &str,
+    params: &str,
+    path: &str,
+    options: &str,
+    control: &QueryControl,
+) -> Result<serde_json::Value> {
+    let started = Instant::now();
+    let result = run_export(handle, query, params, Path::new(path), options, control);
+    record_query(query, params, started.elapsed(), &result);
+    result
+}
+
+fn run_export(
+    handle: &str,
+    query: &str,
+    params: &str,
+    path: &Path,
+    options: &str,
+    control: &QueryControl,
+) -> Result<serde_json::Value> {
+    let options: ExportOptions = if options.is_empty() {
+        ExportOptions::default()
+    } else {
+        serde_json::from_str(options)?
+    };
+    let format = match options.format {
+        Some(format) => format,
+        None => match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => ExportFormat::Csv,
+            Some("ndjson" | "jsonl") => ExportFormat::Ndjson,
+            _ => {
+                return Err(Error::SqlParam(
+                    "format: not given or implied by path".to_owned(),
+                ))
+            }
+        },
+    };
+    let timeout = parse_seconds("timeout", options.timeout)?;
+    let params = params_from_json(params)?;
+    let handle = handle.parse()?;
+    let rows = with_conn(handle, |conn| {
+        let killer = Killer::Mysql(conn.as_ref().connection_id(), conn.opts.clone());
+        control.run(killer, timeout, || {
+            let rows = write_export(conn, query, params, path, format);
+            // Don't leave half an export behind.
+            if rows.is_err() {
+                let _ = std::fs::remove_file(path);
+            }
+            rows
+        })
+    })?;
+    Ok(match rows {
+        Some(rows) => json!({
+            "status": "ok",
+            "rows": rows,
+            "path": std::fs::canonicalize(path)?,
+        }),
+        None => json!({"status": "offline"}),
+    })
+}
+
+// Writes each row as it is read, so the whole result is never held at once.
+fn write_export(
+    conn: &mut PooledConn,
+    query: &str,
+    params: Params,
+    path: &Path,
+    format: ExportFormat,
+) -> Result<u64> {
+    let mut results = conn.exec_iter(query, params)?;
+    let keys = object_keys(results.columns().as_ref());
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut count = 0;
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(file);
+            writer.write_record(&keys)?;
+            for row in results.by_ref() {
+                let serde_json::Value::Array(values) = row_to_json(row?, None)? else {
+                    unreachable!("rows without keys are arrays");
+                };
+                writer.write_record(values.iter().map(json_to_cell))?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::Ndjson => {
+            for row in results.by_ref() {
+                serde_json::to_writer(&mut file, &row_to_json(row?, Some(&keys))?)?;
+                file.write_all(b"\n")?;
+                count += 1;
+            }
+            file.flush()?;
+        }
+    }
+    Ok(count)
+}
+
+// Strings as they are and nulls as nothing, anything else as its json.
+fn json_to_cell(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+// ----------------------------------------------------------------------------
+// Query log
+
+// How many of the slowest queries `sql_slowest_queries` can report.
+const SLOWEST_QUERIES_KEPT: usize = 100;
+
+#[derive(Clone, Serialize)]
+struct QueryRecord {
+    query: String,
+    // For a batch, the number of sets of params.
+    params: usize,
+    // Seconds.
+    duration: f64,
+    // Rows returned, or failing that affected.
+    rows: Option<u64>,
+    error: Option<String>,
+}
+
+impl QueryRecord {
+    fn new(
+        query: &str,
+        params: &str,
+        duration: Duration,
+        result: &Result<serde_json::Value>,
+    ) -> Self {
+        let (rows, error) = match result {
+            Ok(value) => (rows_in(value), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        QueryRecord {
+            query: query.to_owned(),
+            params: count_params(params),
+            duration: duration.as_secs_f64(),
+            rows,
+            error,
+        }
+    }
+
+    #[cfg(feature = "log")]
+    fn to_log_line(&self) -> String {
+        let outcome = match (&self.error, self.rows) {
+            (Some(error), _) => format!("error: {}", error),
+            (None, Some(rows)) => format!("{} rows", rows),
+            (None, None) => "no result".to_owned(),
+        };
+        format!(
+            "{:.3}s, {} params, {}: {}",
+            self.duration, self.params, outcome, self.query
+        )
+    }
+}
+
+// Slowest first. Cleared by `sql_clear_slowest_queries`, such as at the start
+// of a round.
+static SLOWEST: Mutex<Vec<QueryRecord>> = Mutex::new(Vec::new());
+
+// Writes a finished query to the configured log if it took long enough, and
+// keeps it if it is among the slowest. Only builds a record when needed,
+// since this runs after every query.
+fn record_query(query: &str, params: &str, duration: Duration, result: &Result<serde_json::Value>) {
+    let (path, threshold) = config::read(|c| {
+        (
+            c.sql.query_log.clone(),
+            c.sql.slow_query_threshold.unwrap_or(0.0),
+        )
+    });
+    let seconds = duration.as_secs_f64();
+    let mut slowest = SLOWEST.lock().unwrap();
+    let is_slowest = slowest.len() < SLOWEST_QUERIES_KEPT
+        || slowest.last().is_some_and(|last| last.duration < seconds);
+    // Written by the `log` writer, so only when that is built in.
+    let path = path.filter(|_| cfg!(feature = "log") && seconds >= f64::from(threshold));
+    if !is_slowest && path.is_none() {
+        return;
+    }
+    let record = QueryRecord::new(query, params, duration, result);
+    #[cfg(feature = "log")]
+    if let Some(path) = path {
+        crate::log::write(path, record.to_log_line(), false);
+    }
+    if is_slowest {
+        keep_slowest(&mut slowest, record);
+    }
+}
+
+fn keep_slowest(slowest: &mut Vec<QueryRecord>, record: QueryRecord) {
+    let index = slowest.partition_point(|kept| kept.duration >= record.duration);
+    slowest.insert(index, record);
+    slowest.truncate(SLOWEST_QUERIES_KEPT);
+}
+
+fn slowest_queries(count: &str) -> Result<serde_json::Value> {
+    let count: usize = count.parse()?;
+    let slowest = SLOWEST.lock().unwrap();
+    Ok(serde_json::to_value(&slowest[..count.min(slowest.len())])?)
+}
+
+fn count_params(params: &str) -> usize {
+    match serde_json::from_str(params) {
+        Ok(serde_json::Value::Array(values)) => values.len(),
+        Ok(serde_json::Value::Object(values)) => values.len(),
+        _ => 0,
+    }
+}
+
+fn rows_in(result: &serde_json::Value) -> Option<u64> {
+    match result.get("rows") {
+        Some(serde_json::Value::Array(rows)) => Some(rows.len() as u64),
+        // Exports only count them.
+        Some(serde_json::Value::Number(rows)) => rows.as_u64(),
+        _ => result
+            .get("affected")
+            .and_then(|affected| affected.as_u64()),
+    }
+}
+
+// ----------------------------------------------------------------------------
+// Other backends
+
+// Databases other than MySQL, which only support plain queries. Shares
+// `NEXT_ID` with the pools, so a handle is only ever one or the other.
+enum Database {
+    #[cfg(feature = "sql_postgres")]
+    Postgres(Box<postgres::Database>),
+    #[cfg(feature = "sql_sqlite")]
+    Sqlite(sqlite::Database),
+}
+
+static DATABASES: Lazy<DashMap<usize, Arc<Database>>> = Lazy::new(DashMap::new);
+
+fn get_database(handle: usize) -> Option<Arc<Database>> {
+    DATABASES.get(&handle).map(|database| database.clone())
+}
+
+// With no other backends built in, there are no databases to use the arguments.
+#[cfg_attr(
+    not(any(feature = "sql_postgres", feature = "sql_sqlite")),
+    allow(unused_variables)
+)]
+impl Database {
+    fn query(
+        &self,
+        query: &str,
+        params: &str,
+        options: &QueryOptions,
+        control: &QueryControl,
+        timeout: Option<Duration>,
+    ) -> Result<serde_json::Value> {
+        match *self {
+            #[cfg(feature = "sql_postgres")]
+            Database::Postgres(ref database) => {
+                database.query(query, params, options, control, timeout)
+            }
+            #[cfg(feature = "sql_sqlite")]
+            Database::Sqlite(ref database) => {
+                database.query(query, params, options, control, timeout)
+            }
+        }
+    }
+
+    fn ping(&self) -> Result<serde_json::Value> {
+        match *self {
+            #[cfg(feature = "sql_postgres")]
+            Database::Postgres(ref database) => database.ping(),
+            #[cfg(feature = "sql_sqlite")]
+            Database::Sqlite(ref database) => database.ping(),
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+// Transactions
+
+struct Transaction {
+    // Taken out once the transaction has been committed or rolled back.
+    conn: Option<Checkout>,
+    last_used: Instant,
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if let Some(mut conn) = self.conn.take() {
+            let _ = conn.query_drop("ROLLBACK");
+        }
+    }
+}
+
+// Shares `NEXT_ID` with the pools, so a handle is only ever one or the other.
+static TRANSACTIONS: Lazy<DashMap<usize, Arc<Mutex<Transaction>>>> = Lazy::new(DashMap::new);
+
+fn begin(handle: &str, timeout: Option<Duration>) -> Result<serde_json::Value> {
+    let mut conn = match get_pool(handle.parse()?)? {
+        Some(pool) => pool.blocking_checkout()?,
+        None => return Ok(json!({"status": "offline"})),
+    };
+    conn.query_drop("START TRANSACTION")?;
+
+    let timeout = timeout.unwrap_or_else(|| {
+        config::read(|c| c.sql.transaction_timeout)
+            .and_then(|s| Duration::try_from_secs_f32(s).ok())
+            .unwrap_or(DEFAULT_TRANSACTION_TIMEOUT)
+    });
+    let handle = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    TRANSACTIONS.insert(
+        handle,
+        Arc::new(Mutex::new(Transaction {
+            conn: Some(conn),
+            last_used: Instant::now(),
+        })),
+    );
+    watch_transaction(handle, timeout, timeout);
+    Ok(json!({
+        "status": "ok",
+        "handle": handle.to_string(),
+    }))
+}
+
+fn end_transaction(handle: &str, statement: &str) -> Result<serde_json::Value> {
+    let transaction = match TRANSACTIONS.remove(&handle.parse()?) {
+        Some((_, transaction)) => transaction,
+        None => return Ok(json!({"status": "offline"})),
+    };
+    let conn = transaction.lock().unwrap().conn.take();
+    let mut conn = match conn {
+        Some(conn) => conn,
+        None => return Ok(json!({"status": "offline"})),
+    };
+    if let Err(e) = conn.query_drop(statement) {
+        let _ = conn.query_drop("ROLLBACK");
+        return Err(e.into());
+    }
+    Ok(json!({"status": "ok"}))
+}
+
+// Rolls back the transaction once it has sat unused for its timeout, checking
+// again later for as long as it keeps being used. One with a query running is
+// busy, not abandoned, so it is left alone.
+fn watch_transaction(handle: usize, timeout: Duration, after: Duration) {
+    schedule(after, move || {
+        let transaction = match TRANSACTIONS.get(&handle) {
+            Some(transaction) => transaction.clone(),
+            None => return,
+        };
+        let conn = match transaction.try_lock() {
+            Ok(mut t) => match timeout.checked_sub(t.last_used.elapsed()) {
+                Some(left) if !left.is_zero() => return watch_transaction(handle, timeout, left),
+                _ => t.conn.take(),
+            },
+            Err(_) => return watch_transaction(handle, timeout, timeout),
+        };
+        TRANSACTIONS.remove(&handle);
+        if let Some(mut conn) = conn {
+            // Timers have to be quick, and this is a round trip to the server.
+            jobs::spawn("sql", move || {
+                let _ = conn.query_drop("ROLLBACK");
+            });
+        }
+    });
+}
+
+// ----------------------------------------------------------------------------
+// Cursors
+
+// Each cursor's query runs as a task on the job pool, which holds the
+// connection and reads rows off it as they are asked for. The task gives up,
+// closing the cursor, once it goes unused for the cursor timeout.
+struct Cursor {
+    fetch: flume::Sender<usize>,
+    batches: flume::Receiver<Result<serde_json::Value>>,
+    // A batch has been asked for but not yet collected.
+    pending: bool,
+}
+
+// Shares `NEXT_ID` with the pools and transactions.
+static CURSORS: Lazy<DashMap<usize, Arc<Mutex<Cursor>>>> = Lazy::new(DashMap::new);
+
+fn open_cursor(
+    handle: &str,
+    query: &str,
+    params: &str,
+    options: &str,
+) -> Result<serde_json::Value> {
+    let handle = handle.parse()?;
+    let options = parse_query_options(options)?;
+    let params = params_from_json(params)?;
+    let query = query.to_owned();
+    let timeout = cursor_timeout();
+
+    let (fetch, fetch_rx) = flume::bounded::<usize>(1);
+    let (batch_tx, batches) = flume::bounded(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let cursor = Cursor {
+        fetch,
+        batches,
+        pending: false,
+    };
+    CURSORS.insert(id, Arc::new(Mutex::new(cursor)));
+    // Not the `sql` queue, so cursors waiting on fetches can't hold up the
+    // queries fetching from them.
+    jobs::spawn(CURSOR_QUEUE, move || {
+        let outcome = with_conn(handle, |conn| {
+            let mut query_result = conn.exec_iter(query, params)?;
+            let columns: Vec<_> = query_result
+                .columns()
+                .as_ref()
+                .iter()
+                .map(column_to_json)
+                .collect();
+            let keys = options
+                .rows_as_objects
+                .then(|| object_keys(query_result.columns().as_ref()));
+            // Ends when the cursor is closed, dropping the sender, or times out.
+            while let Ok(count) = fetch_rx.recv_timeout(timeout) {
+                let batch = read_batch(&mut query_result, count, keys.as_deref());
+                let last = !matches!(batch, Ok((_, false)));
+                let batch = batch.map(|(rows, done)| {
+                    json!({
+                        "status": "ok",
+                        "columns": columns,
+                        "rows": rows,
+                        "done": done,
+                    })
+                });
+                if batch_tx.send(batch).is_err() || last {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        // The query never started, so tell whoever fetches first why.
+        let answer = match outcome {
+            Ok(Some(())) => None,
+            Ok(None) => Some(Ok(json!({"status": "offline"}))),
+            Err(e) => Some(Err(e)),
+        };
+        if let Some(answer) = answer {
+            if fetch_rx.recv_timeout(timeout).is_ok() {
+                let _ = batch_tx.send(answer);
+            }
+        }
+        // Already gone if it was closed or read to the end.
+        CURSORS.remove(&id);
+    });
+    Ok(json!({
+        "status": "ok",
+        "cursor": id.to_string(),
+    }))
+}
+
+// Up to `count` rows, and whether they were the last.
+fn read_batch(
+    rows: &mut impl Iterator<Item = mysql::Result<mysql::Row>>,
+    count: usize,
+    keys: Option<&[String]>,
+) -> Result<(Vec<serde_json::Value>, bool)> {
+    let mut batch = Vec::new();
+    while batch.len() < count {
+        match rows.next() {
+            Some(row) => batch.push(row_to_json(row?, keys)?),
+            None => return Ok((batch, true)),
+        }
+    }
+    Ok((batch, false))
+}
+
+fn cursor_timeout() -> Duration {
+    config::read(|c| c.sql.cursor_timeout)
+        .and_then(|s| Duration::try_from_secs_f32(s).ok())
+        .unwrap_or(DEFAULT_CURSOR_TIMEOUT)
+}
+
+// Gives up with a status of "busy" if the rows take longer than `wait`, in
+// which case the next fetch collects them rather than asking for more.
+fn fetch_cursor(id: &str, count: &str, wait: Duration) -> Result<serde_json::Value> {
+    let id = id.parse()?;
+    let count = count.parse::<usize>()?.max(1);
+    let cursor = match CURSORS.get(&id).map(|c| c.clone()) {
+        Some(cursor) => cursor,
+        None => return Ok(json!({"status": "offline"})),
+    };
+    let mut cursor = match cursor.try_lock() {
+        Ok(cursor) => cursor,
+        // Another fetch is already waiting on it.
+        Err(_) => return Ok(json!({"status": "busy"})),
+    };
+    let asked = cursor.pending || cursor.fetch.try_send(count).is_ok();
+    let batch = if asked {
+        match cursor.batches.recv_timeout(wait) {
+            Ok(batch) => Some(batch),
+            Err(flume::RecvTimeoutError::Timeout) => {
+                cursor.pending = true;
+                return Ok(json!({"status": "busy"}));
+            }
+            Err(flume::RecvTimeoutError::Disconnected) => None,
+        }
+    } else {
+        None
+    };
+    cursor.pending = false;
+    let finished = match &batch {
+        Some(Ok(batch)) => batch["done"] != false,
+        _ => true,
+    };
+    if finished {
+        CURSORS.remove(&id);
+    }
+    batch.unwrap_or_else(|| Ok(json!({"status": "offline"})))
+}
+
+fn close_cursor(id: &str) -> Result<serde_json::Value> {
+    Ok(match CURSORS.remove(&id.parse()?) {
+        Some(_) => json!({"status": "success"}),
+        None => json!({"status": "offline"}),
+    })
+}
+
+// ----------------------------------------------------------------------------
+// Migrations
+
+const MIGRATIONS_TABLE: &str = "rustg_schema_migrations";
+// Held while migrating, so two servers sharing a database take turns.
+const MIGRATIONS_LOCK: &str = "rustg_schema_migrations";
+const MIGRATIONS_LOCK_TIMEOUT: u32 = 60;
+
+struct Migration {
+    version: u64,
+    name: String,
+    sql: String,
+    checksum: String,
+}
+
+// Every `<version>_<description>.sql` in `dir`, in version order.
+fn read_migrations(dir: &Path) -> Result<Vec<Migration>> {
+    let mut migrations = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("sql") {
+            continue;
+        }
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let version = name
+            .split(|c: char| !c.is_ascii_digit())
+            .next()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| {
+                Error::SqlMigration(format!("{} does not start with a version number", name))
+            })?;
+        let sql = std::fs::read_to_string(&path)?;
+        let checksum = Sha256::digest(sql.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        migrations.push(Migration {
+            version,
+            name,
+            sql,
+            checksum,
+        });
+    }
+    migrations.sort_by_key(|m| m.version);
+    if let Some(pair) = migrations.windows(2).find(|w| w[0].version == w[1].version) {
+        return Err(Error::SqlMigration(format!(
+            "{} and {} have the same version",
+            pair[0].name, pair[1].name
+        )));
+    }
+    Ok(migrations)
+}
+
+fn migrate(handle: &str, dir: &str) -> Result<serde_json::Value> {
+    let migrations = read_migrations(Path::new(dir))?;
+    let mut conn = match get_pool(handle.parse()?)? {
+        Some(pool) => pool.blocking_checkout()?,
+        None => return Ok(json!({"status": "offline"})),
+    };
+
+    let locked: Option<Option<u8>> = conn.exec_first(
+        "SELECT GET_LOCK(?, ?)",
+        (MIGRATIONS_LOCK, MIGRATIONS_LOCK_TIMEOUT),
+    )?;
+    if locked.flatten() != Some(1) {
+        return Err(Error::SqlMigration(
+            "timed out waiting for another server to finish migrating".to_owned(),
+        ));
+    }
+    let result = apply_migrations(&mut conn, &migrations);
+    let _ = conn.exec_drop("SELECT RELEASE_LOCK(?)", (MIGRATIONS_LOCK,));
+    result
+}
+
+fn apply_migrations(conn: &mut PooledConn, migrations: &[Migration]) -> Result<serde_json::Value> {
+    conn.query_drop(format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+            version BIGINT UNSIGNED NOT NULL PRIMARY KEY,
+            name VARCHAR(255) NOT NULL,
+            checksum CHAR(64) NOT NULL,
+            applied_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+        MIGRATIONS_TABLE
+    ))?;
+    let applied: HashMap<u64, (String, String)> = conn
+        .query::<(u64, String, String), _>(format!(
+            "SELECT version, name, checksum FROM {}",
+            MIGRATIONS_TABLE
+        ))?
+        .into_iter()
+        .map(|(version, name, checksum)| (version, (name, checksum)))
+        .collect();
+
+    // Check everything up front, so nothing runs against a schema which has
+    // drifted from the files.
+    for migration in migrations {
+        if let Some((name, checksum)) = applied.get(&migration.version) {
+            if *checksum != migration.checksum {
+                return Err(Error::SqlMigration(format!(
+                    "{} has changed since it was applied as {}",
+                    migration.name, name
+                )));
+            }
+        }
+    }
+
+    let mut ran = Vec::new();
+    for migration in migrations {
+        if applied.contains_key(&migration.version) {
+            continue;
+        }
+        // MySQL commits schema changes as it goes, so a failure here leaves
+        // the earlier statements of this file applied.
+        if let Err(e) = run_script(conn, &migration.sql) {
+            return Ok(json!({
+                "status": "err",
+                "data": format!("{}: {}", migration.name, e),
+                "applied": ran,
+            }));
+        }
+        conn.exec_drop(
+            format!(
+                "INSERT INTO {} (version, name, checksum) VALUES (?, ?, ?)",
+                MIGRATIONS_TABLE
+            ),
+            (migration.version, &migration.name, &migration.checksum),
+        )?;
+        ran.push(json!({"version": migration.version, "name": migration.name}));
+    }
+
+    let version = migrations
+        .iter()
+        .map(|m| m.version)
+        .chain(applied.keys().copied())
+        .max();
+    Ok(json!({
+        "status": "ok",
+        "applied": ran,
+        "version": version,
+    }))
+}
+
+// Runs every statement of `sql`, stopping at the first which fails.
+// `query_drop` would only report an error from the first one, as dropping the
+// result throws away the errors of the result sets after it.
+fn run_script(conn: &mut PooledConn, sql: &str) -> Result<()> {
+    let mut result = conn.query_iter(sql)?;
+    while let Some(set) = result.iter() {
+        for row in set {
+            row?;
+        }
+    }
+    Ok(())
+}
+
+// ----------------------------------------------------------------------------
+// Helpers
+
+// See the comment at the top of `dmsrc/sql.dm` for what each type becomes.
+fn mysql_to_json(col: &Column, value: &mysql::Value) -> serde_json::Value {
+    let ctype = col.column_type();
+    let flags = col.flags();
+    match value {
+        mysql::Value::NULL => serde_json::Value::Null,
+        mysql::Value::Bytes(b) => match ctype {
+            MYSQL_TYPE_DECIMAL | MYSQL_TYPE_NEWDECIMAL => {
+                // Kept as a string so no precision is lost.
+                serde_json::Value::String(String::from_utf8_lossy(b).into_owned())
+            }
+            MYSQL_TYPE_JSON => serde_json::from_slice(b).unwrap_or_else(|_| {
+                serde_json::Value::String(String::from_utf8_lossy(b).into_owned())
+            }),
+            MYSQL_TYPE_BIT if b.len() <= 8 => serde_json::Value::Number(Number::from(
+                b.iter().fold(0u64, |acc, byte| acc << 8 | u64::from(*byte)),
+            )),
+            MYSQL_TYPE_SET => set_to_json(b),
+            MYSQL_TYPE_STRING if flags.contains(ColumnFlags::SET_FLAG) => set_to_json(b),
+            MYSQL_TYPE_BLOB
+            | MYSQL_TYPE_LONG_BLOB
+            | MYSQL_TYPE_MEDIUM_BLOB
+            | MYSQL_TYPE_TINY_BLOB
+                if flags.contains(ColumnFlags::BINARY_FLAG) =>
+            {
+                bytes_to_json(b)
+            }
+            MYSQL_TYPE_BIT | MYSQL_TYPE_GEOMETRY => bytes_to_json(b),
+            // Numbers only arrive as text from servers not using the binary
+            // protocol for this query.
+            MYSQL_TYPE_TINY | MYSQL_TYPE_SHORT | MYSQL_TYPE_INT24 | MYSQL_TYPE_LONG
+            | MYSQL_TYPE_LONGLONG | MYSQL_TYPE_YEAR => {
+                let text = String::from_utf8_lossy(b);
+                if let Ok(u) = text.parse::<u64>() {
+                    serde_json::Value::Number(Number::from(u))
+                } else if let Ok(i) = text.parse::<i64>() {
+                    serde_json::Value::Number(Number::from(i))
+                } else {
+                    serde_json::Value::String(text.into_owned())
+                }
+            }
+            MYSQL_TYPE_FLOAT | MYSQL_TYPE_DOUBLE => {
+                let text = String::from_utf8_lossy(b);
+                match text.parse::<f64>().ok().and_then(Number::from_f64) {
+                    Some(n) => serde_json::Value::Number(n),
+                    None => serde_json::Value::String(text.into_owned()),
+                }
+            }
+            // Strings, ENUMs, and dates and times sent as text.
+            _ => serde_json::Value::String(String::from_utf8_lossy(b).into_owned()),
+        },
+        mysql::Value::Float(f) => serde_json::Value::Number(
+            Number::from_f64(f64::from(*f)).unwrap_or_else(|| Number::from(0)),
+        ),
+        mysql::Value::Double(f) => {
+            serde_json::Value::Number(Number::from_f64(*f).unwrap_or_else(|| Number::from(0)))
+        }
+        mysql::Value::Int(i) => serde_json::Value::Number(Number::from(*i)),
+        mysql::Value::UInt(u) => serde_json::Value::Number(Number::from(*u)),
+        mysql::Value::Date(year, month, day, hour, minute, second, micros) => {
+            let mut date = format!(
+                "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+                year, month, day, hour, minute, second
+            );
+            if *micros != 0 {
+                date.push_str(&format!(".{:06}", micros));
+            }
+            serde_json::Value::String(date)
+        }
+        mysql::Value::Time(negative, days, hours, minutes, seconds, micros) => {
+            // A duration in seconds, which is what a TIME is more often than
+            // not, and what DM can do arithmetic on.
+            let whole = ((u64::from(*days) * 24 + u64::from(*hours)) * 60 + u64::from(*minutes))
+                * 60
+                + u64::from(*seconds);
+            let total = whole as f64 + f64::from(*micros) / 1_000_000.0;
+            let total = if *negative { -total } else { total };
+            Number::from_f64(total)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null)
+        }
+    }
+}
+
+// Keys for rows returned as objects. A name seen before, as from a join, is
+// qualified with its table (`table.name`), and failing that numbered
+// (`name_2`, `name_3`, ...), so no column is lost.
+fn object_keys(columns: &[Column]) -> Vec<String> {
+    unique_keys(columns.iter().map(|col| (col.name_str(), col.table_str())))
+}
+
+// `object_keys` for any backend, from each column's name and table.
+fn unique_keys<N: AsRef<str>, T: AsRef<str>>(
+    columns: impl IntoIterator<Item = (N, T)>,
+) -> Vec<String> {
+    let mut keys: Vec<String> = Vec::new();
+    for (name, table) in columns {
+        let (name, table) = (name.as_ref(), table.as_ref());
+        let mut key = name.to_string();
+        if keys.contains(&key) && !table.is_empty() {
+            key = format!("{}.{}", table, name);
+        }
+        let mut n = 2;
+        while keys.contains(&key) {
+            key = format!("{}_{}", name, n);
+            n += 1;
+        }
+        keys.push(key);
+    }
+    keys
+}
+
+fn bytes_to_json(b: &[u8]) -> serde_json::Value {
+    serde_json::Value::Array(
+        b.iter()
+            .map(|x| serde_json::Value::Number(Number::from(*x)))
+            .collect(),
+    )
+}
+
+fn set_to_json(b: &[u8]) -> serde_json::Value {
+    let text = String::from_utf8_lossy(b);
+    serde_json::Value::Array(
+        text.split(',')
+            .filter(|member| !member.is_empty())
+            .map(|member| serde_json::Value::String(member.to_owned()))
+            .collect(),
+    )
+}
+
+fn column_type_name(col: &Column) -> &'static str {
+    let flags = col.flags();
+    let binary = flags.contains(ColumnFlags::BINARY_FLAG);
+    match col.column_type() {
+        MYSQL_TYPE_DECIMAL | MYSQL_TYPE_NEWDECIMAL => "decimal",
+        MYSQL_TYPE_TINY => "tinyint",
+        MYSQL_TYPE_SHORT => "smallint",
+        MYSQL_TYPE_INT24 => "mediumint",
+        MYSQL_TYPE_LONG => "int",
+        MYSQL_TYPE_LONGLONG => "bigint",
+        MYSQL_TYPE_FLOAT => "float",
+        MYSQL_TYPE_DOUBLE => "double",
+        MYSQL_TYPE_NULL => "null",
+        MYSQL_TYPE_TIMESTAMP | MYSQL_TYPE_TIMESTAMP2 => "timestamp",
+        MYSQL_TYPE_DATE | MYSQL_TYPE_NEWDATE => "date",
+        MYSQL_TYPE_TIME | MYSQL_TYPE_TIME2 => "time",
+        MYSQL_TYPE_DATETIME | MYSQL_TYPE_DATETIME2 => "datetime",
+        MYSQL_TYPE_YEAR => "year",
+        MYSQL_TYPE_BIT => "bit",
+        MYSQL_TYPE_JSON => "json",
+        MYSQL_TYPE_ENUM => "enum",
+        MYSQL_TYPE_SET => "set",
+        MYSQL_TYPE_GEOMETRY => "geometry",
+        MYSQL_TYPE_STRING if flags.contains(ColumnFlags::ENUM_FLAG) => "enum",
+        MYSQL_TYPE_STRING if flags.contains(ColumnFlags::SET_FLAG) => "set",
+        MYSQL_TYPE_STRING if binary => "binary",
+        MYSQL_TYPE_STRING => "char",
+        MYSQL_TYPE_VARCHAR | MYSQL_TYPE_VAR_STRING if binary => "varbinary",
+        MYSQL_TYPE_VARCHAR | MYSQL_TYPE_VAR_STRING => "varchar",
+        MYSQL_TYPE_TINY_BLOB | MYSQL_TYPE_MEDIUM_BLOB | MYSQL_TYPE_LONG_BLOB | MYSQL_TYPE_BLOB
+            if binary =>
+        {
+            "blob"
+        }
+        MYSQL_TYPE_TINY_BLOB | MYSQL_TYPE_MEDIUM_BLOB | MYSQL_TYPE_LONG_BLOB | MYSQL_TYPE_BLOB => {
+            "text"
+        }
+        MYSQL_TYPE_TYPED_ARRAY | MYSQL_TYPE_UNKNOWN => "unknown",
+    }
+}
+
+fn column_to_json(col: &Column) -> serde_json::Value {
+    const FLAG_NAMES: &[(ColumnFlags, &str)] = &[
+        (ColumnFlags::PRI_KEY_FLAG, "primary_key"),
+        (ColumnFlags::UNIQUE_KEY_FLAG, "unique_key"),
+        (ColumnFlags::MULTIPLE_KEY_FLAG, "multiple_key"),
+        (ColumnFlags::UNSIGNED_FLAG, "unsigned"),
+        (ColumnFlags::ZEROFILL_FLAG, "zerofill"),
+        (ColumnFlags::BINARY_FLAG, "binary"),
+        (ColumnFlags::AUTO_INCREMENT_FLAG, "auto_increment"),
+        (ColumnFlags::NO_DEFAULT_VALUE_FLAG, "no_default_value"),
+        (ColumnFlags::ON_UPDATE_NOW_FLAG, "on_update_now"),
+    ];
+    let flags = col.flags();
+    json!({
+        "name": col.name_str(),
+        "type": column_type_name(col),
+        "nullable": !flags.contains(ColumnFlags::NOT_NULL_FLAG),
+        "flags": FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| flags.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>(),
+        "table": col.table_str(),
+        "length": col.column_length(),
+        "decimals": col.decimals(),
+    })
+}
+
+// Metadata for backends which only know a column's name and type.
+#[cfg(any(feature = "sql_postgres", feature = "sql_sqlite"))]
+fn plain_column_to_json(name: &str, type_name: &str) -> serde_json::Value {
+    json!({
+        "name": name,
+        "type": type_name,
+        "nullable": true,
+        "flags": [],
+        "table": "",
+        "length": 0,
+        "decimals": 0,
+    })
+}
+
+// Dates and times as the text other backends parse them from, in the format
+// MySQL results use (times as `hh:mm:ss`, with hours past 24 for days).
+#[cfg(any(feature = "sql_postgres", feature = "sql_sqlite"))]
+fn temporal_to_string(value: &mysql::Value) -> Option<String> {
+    let (text, micros) = match *value {
+        mysql::Value::Date(year, month, day, hour, minute, second, micros) => (
+            format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                year, month, day, hour, minute, second
+            ),
+            micros,
+        ),
+        mysql::Value::Time(negative, days, hours, minutes, seconds, micros) => (
+            format!(
+                "{}{:02}:{:02}:{:02}",
+                if negative { "-" } else { "" },
+                days * 24 + u32::from(hours),
+                minutes,
+                seconds
+            ),
+            micros,
         ),
+        _ => return None,
+    };
+    Some(if micros == 0 {
+        text
+    } else {
+        format!("{}.{:06}", text, micros)
+    })
+}
+
+fn err_to_json<E: std::fmt::Display>(e: E) -> String {
+    json!({
+        "status": "err",
+        "data": &e.to_string()
+    })
+    .to_string()
+}
+
+fn json_to_mysql(val: serde_json::Value) -> Result<mysql::Value> {
+    Ok(match val {
+        serde_json::Value::Bool(b) => mysql::Value::UInt(b as u64),
+        serde_json::Value::Number(i) => {
+            if let Some(v) = i.as_u64() {
+                mysql::Value::UInt(v)
+            } else if let Some(v) = i.as_i64() {
+                mysql::Value::Int(v)
+            } else if let Some(v) = i.as_f64() {
+                mysql::Value::Double(v)
+            } else {
+                mysql::Value::NULL
+            }
+        }
+        serde_json::Value::String(s) => mysql::Value::Bytes(s.into()),
+        serde_json::Value::Array(a) => mysql::Value::Bytes(
+            a.into_iter()
+                .map(|x| {
+                    if let serde_json::Value::Number(n) = x {
+                        n.as_u64().unwrap_or(0) as u8
+                    } else {
+                        0
+                    }
+                })
+                .collect(),
+        ),
+        serde_json::Value::Object(o) if o.contains_key("type") => typed_to_mysql(o)?,
         _ => mysql::Value::NULL,
+    })
+}
+
+// A parameter written as `{"type": ..., "value": ...}`, for values plain JSON
+// can't carry exactly. See `dmsrc/sql.dm` for the types.
+fn typed_to_mysql(mut param: Map<String, serde_json::Value>) -> Result<mysql::Value> {
+    use serde_json::Value as Json;
+
+    let kind = match param.remove("type") {
+        Some(Json::String(kind)) => kind,
+        _ => return Err(Error::SqlParam("`type` must be a string".to_owned())),
+    };
+    // Checked first, so a mistyped type name isn't passed off as NULL.
+    if !matches!(
+        kind.as_str(),
+        "null"
+            | "int"
+            | "double"
+            | "decimal"
+            | "string"
+            | "json"
+            | "blob_base64"
+            | "datetime"
+            | "date"
+            | "time"
+    ) {
+        return Err(Error::SqlParam(format!("unknown type `{}`", kind)));
+    }
+    let value = param.remove("value").unwrap_or(Json::Null);
+    let invalid = |value: &Json| Error::SqlParam(format!("{} is not a valid {}", value, kind));
+    Ok(match (kind.as_str(), value) {
+        ("null", _) | (_, Json::Null) => mysql::Value::NULL,
+        ("int", Json::Number(n)) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => mysql::Value::Int(i),
+            (None, Some(u)) => mysql::Value::UInt(u),
+            _ => return Err(invalid(&Json::Number(n))),
+        },
+        ("int", Json::String(s)) => match (s.parse::<i64>(), s.parse::<u64>()) {
+            (Ok(i), _) => mysql::Value::Int(i),
+            (_, Ok(u)) => mysql::Value::UInt(u),
+            _ => return Err(invalid(&Json::String(s))),
+        },
+        ("double", Json::Number(n)) => mysql::Value::Double(n.as_f64().unwrap_or_default()),
+        ("double", Json::String(s)) => match s.parse::<f64>() {
+            Ok(f) => mysql::Value::Double(f),
+            Err(_) => return Err(invalid(&Json::String(s))),
+        },
+        // Sent as text so the server parses it, rather than through a float.
+        ("decimal", Json::Number(n)) => mysql::Value::Bytes(n.to_string().into()),
+        ("decimal", Json::String(s)) if is_decimal(&s) => mysql::Value::Bytes(s.into()),
+        ("string", Json::String(s)) => mysql::Value::Bytes(s.into()),
+        ("json", value) => mysql::Value::Bytes(value.to_string().into()),
+        ("blob_base64", Json::String(s)) => match base64::decode(&s) {
+            Ok(bytes) => mysql::Value::Bytes(bytes),
+            Err(_) => return Err(invalid(&Json::String(s))),
+        },
+        ("datetime" | "date", Json::String(s)) => match parse_datetime(&s) {
+            Some(date) => date,
+            None => return Err(invalid(&Json::String(s))),
+        },
+        ("time", Json::Number(n)) => match n.as_f64() {
+            Some(seconds) if seconds.is_finite() => seconds_to_time(seconds),
+            _ => return Err(invalid(&Json::Number(n))),
+        },
+        ("time", Json::String(s)) => match parse_time(&s) {
+            Some(time) => time,
+            None => return Err(invalid(&Json::String(s))),
+        },
+        (_, value) => return Err(invalid(&value)),
+    })
+}
+
+fn is_decimal(s: &str) -> bool {
+    let digits = s.strip_prefix(['-', '+']).unwrap_or(s);
+    let mut parts = digits.splitn(2, '.');
+    let whole = parts.next().unwrap_or("");
+    let fraction = parts.next().unwrap_or("");
+    !(whole.is_empty() && fraction.is_empty())
+        && whole.chars().all(|c| c.is_ascii_digit())
+        && fraction.chars().all(|c| c.is_ascii_digit())
+}
+
+// `YYYY-MM-DD`, optionally followed by ` hh:mm:ss` (or `T` in place of the
+// space) and fractional seconds.
+fn parse_datetime(s: &str) -> Option<mysql::Value> {
+    let (date, time) = match s.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+    let mut date = date.splitn(3, '-');
+    let year = date.next()?.parse().ok()?;
+    let month = date.next()?.parse().ok()?;
+    let day = date.next()?.parse().ok()?;
+    let (hour, minute, second, micros) = match time {
+        Some(time) => {
+            let (hms, micros) = split_fraction(time)?;
+            let mut hms = hms.splitn(3, ':');
+            let hour = hms.next()?.parse().ok()?;
+            let minute = hms.next()?.parse().ok()?;
+            let second = hms.next()?.parse().ok()?;
+            (hour, minute, second, micros)
+        }
+        None => (0, 0, 0, 0),
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
     }
+    Some(mysql::Value::Date(
+        year, month, day, hour, minute, second, micros,
+    ))
 }
 
-fn array_to_params(params: Vec<serde_json::Value>) -> Params {
-    if params.is_empty() {
+// `[-]hhh:mm:ss` with optional fractional seconds, as MySQL writes a TIME.
+fn parse_time(s: &str) -> Option<mysql::Value> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let (hms, micros) = split_fraction(s)?;
+    let mut hms = hms.splitn(3, ':');
+    let hours: u32 = hms.next()?.parse().ok()?;
+    let minutes: u8 = hms.next()?.parse().ok()?;
+    let seconds: u8 = hms.next()?.parse().ok()?;
+    if minutes > 59 || seconds > 59 {
+        return None;
+    }
+    Some(mysql::Value::Time(
+        negative,
+        hours / 24,
+        (hours % 24) as u8,
+        minutes,
+        seconds,
+        micros,
+    ))
+}
+
+fn seconds_to_time(seconds: f64) -> mysql::Value {
+    let micros_total = (seconds.abs() * 1_000_000.0).round() as u64;
+    let whole = micros_total / 1_000_000;
+    mysql::Value::Time(
+        seconds < 0.0,
+        (whole / 86_400) as u32,
+        (whole / 3600 % 24) as u8,
+        (whole / 60 % 60) as u8,
+        (whole % 60) as u8,
+        (micros_total % 1_000_000) as u32,
+    )
+}
+
+// Splits `ss.ffffff` into the part before the point and the microseconds.
+fn split_fraction(s: &str) -> Option<(&str, u32)> {
+    match s.split_once('.') {
+        Some((whole, fraction)) => {
+            if fraction.is_empty()
+                || fraction.len() > 6
+                || !fraction.chars().all(|c| c.is_ascii_digit())
+            {
+                return None;
+            }
+            let micros = format!("{:0<6}", fraction).parse().ok()?;
+            Some((whole, micros))
+        }
+        None => Some((s, 0)),
+    }
+}
+
+fn array_to_params(params: Vec<serde_json::Value>) -> Result<Params> {
+    Ok(if params.is_empty() {
         Params::Empty
     } else {
-        Params::Positional(params.into_iter().map(json_to_mysql).collect())
-    }
+        Params::Positional(
+            params
+                .into_iter()
+                .map(json_to_mysql)
+                .collect::<Result<_>>()?,
+        )
+    })
 }
 
-fn object_to_params(params: Map<std::string::String, serde_json::Value>) -> Params {
-    if params.is_empty() {
+fn object_to_params(params: Map<std::string::String, serde_json::Value>) -> Result<Params> {
+    Ok(if params.is_empty() {
         Params::Empty
     } else {
         Params::Named(
             params
                 .into_iter()
-                .map(|(key, val)| (key, json_to_mysql(val)))
-                .collect(),
+                .map(|(key, val)| Ok((key, json_to_mysql(val)?)))
+                .collect::<Result<_>>()?,
         )
-    }
+    })
 }
 
-fn params_from_json(params: &str) -> Params {
+fn params_from_json(params: &str) -> Result<Params> {
     match serde_json::from_str(params) {
-        Ok(serde_json::Value::Object(o)) => object_to_params(o),
-        Ok(serde_json::Value::Array(a)) => array_to_params(a),
-        _ => Params::Empty,
+        Ok(params) => params_from_value(params),
+        Err(_) => Ok(Params::Empty),
+    }
+}
+
+fn params_from_value(params: serde_json::Value) -> Result<Params> {
+    match params {
+        serde_json::Value::Object(o) => object_to_params(o),
+        serde_json::Value::Array(a) => array_to_params(a),
+        _ => Ok(Params::Empty),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mysql::consts::ColumnType;
+
+    fn convert(ctype: ColumnType, flags: ColumnFlags, value: mysql::Value) -> serde_json::Value {
+        mysql_to_json(&Column::new(ctype).with_flags(flags), &value)
+    }
+
+    fn bytes(ctype: ColumnType, text: &str) -> serde_json::Value {
+        convert(
+            ctype,
+            ColumnFlags::empty(),
+            mysql::Value::Bytes(text.into()),
+        )
+    }
+
+    #[test]
+    fn column_types_convert() {
+        assert_eq!(bytes(MYSQL_TYPE_NEWDECIMAL, "12.50"), json!("12.50"));
+        assert_eq!(bytes(MYSQL_TYPE_JSON, r#"{"a": [1]}"#), json!({"a": [1]}));
+        assert_eq!(bytes(MYSQL_TYPE_ENUM, "red"), json!("red"));
+        assert_eq!(bytes(MYSQL_TYPE_SET, "a,c"), json!(["a", "c"]));
+        assert_eq!(bytes(MYSQL_TYPE_SET, ""), json!([]));
+        assert_eq!(
+            convert(
+                MYSQL_TYPE_STRING,
+                ColumnFlags::SET_FLAG,
+                mysql::Value::Bytes(b"x".to_vec())
+            ),
+            json!(["x"])
+        );
+        assert_eq!(
+            convert(
+                MYSQL_TYPE_BIT,
+                ColumnFlags::empty(),
+                mysql::Value::Bytes(vec![1, 2])
+            ),
+            json!(258)
+        );
+        assert_eq!(
+            convert(
+                MYSQL_TYPE_BLOB,
+                ColumnFlags::BINARY_FLAG,
+                mysql::Value::Bytes(vec![0, 255])
+            ),
+            json!([0, 255])
+        );
+        assert_eq!(bytes(MYSQL_TYPE_BLOB, "text"), json!("text"));
+        assert_eq!(bytes(MYSQL_TYPE_LONGLONG, "-5"), json!(-5));
+        assert_eq!(
+            convert(
+                MYSQL_TYPE_DATETIME,
+                ColumnFlags::empty(),
+                mysql::Value::Date(2020, 1, 2, 3, 4, 5, 250_000)
+            ),
+            json!("2020-01-02 03:04:05.250000")
+        );
+        assert_eq!(
+            convert(
+                MYSQL_TYPE_TIME,
+                ColumnFlags::empty(),
+                mysql::Value::Time(true, 1, 2, 0, 30, 500_000)
+            ),
+            json!(-(26.0 * 3600.0 + 30.5))
+        );
+        assert_eq!(
+            convert(MYSQL_TYPE_LONG, ColumnFlags::empty(), mysql::Value::NULL),
+            serde_json::Value::Null
+        );
+    }
+
+    #[test]
+    fn column_metadata() {
+        let col = Column::new(MYSQL_TYPE_LONG)
+            .with_name(b"id")
+            .with_flags(ColumnFlags::NOT_NULL_FLAG | ColumnFlags::PRI_KEY_FLAG);
+        let meta = column_to_json(&col);
+        assert_eq!(meta["name"], "id");
+        assert_eq!(meta["type"], "int");
+        assert_eq!(meta["nullable"], false);
+        assert_eq!(meta["flags"], json!(["primary_key"]));
+    }
+
+    #[test]
+    fn typed_params_convert() {
+        let params = params_from_json(
+            r#"[
+                1.25,
+                {"type": "double", "value": "0.1"},
+                {"type": "decimal", "value": "-12.345"},
+                {"type": "datetime", "value": "2020-01-02 03:04:05.5"},
+                {"type": "date", "value": "2020-01-02"},
+                {"type": "time", "value": -90.5},
+                {"type": "time", "value": "26:00:01"},
+                {"type": "blob_base64", "value": "AP8="},
+                {"type": "json", "value": {"a": 1}},
+                {"type": "null"}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            Params::Positional(vec![
+                mysql::Value::Double(1.25),
+                mysql::Value::Double(0.1),
+                mysql::Value::Bytes(b"-12.345".to_vec()),
+                mysql::Value::Date(2020, 1, 2, 3, 4, 5, 500_000),
+                mysql::Value::Date(2020, 1, 2, 0, 0, 0, 0),
+                mysql::Value::Time(true, 0, 0, 1, 30, 500_000),
+                mysql::Value::Time(false, 1, 2, 0, 1, 0),
+                mysql::Value::Bytes(vec![0, 255]),
+                mysql::Value::Bytes(br#"{"a":1}"#.to_vec()),
+                mysql::Value::NULL,
+            ])
+        );
+
+        for bad in [
+            r#"[{"type": "decimal", "value": "1e5"}]"#,
+            r#"[{"type": "datetime", "value": "2020-13-01"}]"#,
+            r#"[{"type": "blob_base64", "value": "!"}]"#,
+            r#"[{"type": "int", "value": 1.5}]"#,
+            r#"{"x": {"type": "nonsense", "value": 1}}"#,
+            r#"[{"type": "nonsense"}]"#,
+            r#"[{"type": "nonsense", "value": null}]"#,
+        ] {
+            assert!(params_from_json(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn duplicate_column_keys() {
+        let columns = [
+            Column::new(MYSQL_TYPE_LONG)
+                .with_name(b"id")
+                .with_table(b"a"),
+            Column::new(MYSQL_TYPE_LONG)
+                .with_name(b"id")
+                .with_table(b"b"),
+            Column::new(MYSQL_TYPE_LONG).with_name(b"id"),
+            Column::new(MYSQL_TYPE_LONG).with_name(b"name"),
+        ];
+        assert_eq!(object_keys(&columns), ["id", "b.id", "id_2", "name"]);
+    }
+
+    #[test]
+    fn migrations_are_ordered_and_checked() {
+        let dir = std::env::temp_dir().join(format!("rustg-migrations-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("10_later.sql"), "SELECT 10;").unwrap();
+        std::fs::write(dir.join("2_first.sql"), "SELECT 2;").unwrap();
+        std::fs::write(dir.join("notes.txt"), "ignored").unwrap();
+        let migrations = read_migrations(&dir).unwrap();
+        let versions: Vec<_> = migrations.iter().map(|m| m.version).collect();
+        assert_eq!(versions, [2, 10]);
+        assert_eq!(migrations[0].checksum.len(), 64);
+
+        std::fs::write(dir.join("02_clash.sql"), "SELECT 2;").unwrap();
+        assert!(read_migrations(&dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backoff_doubles_and_resets() {
+        let stats = PoolStats::default();
+        assert!(stats.retry_in().is_none());
+        stats.failed_to_connect();
+        let first = stats.retry_in().unwrap();
+        assert!(first <= RECONNECT_BACKOFF_MIN);
+        stats.failed_to_connect();
+        assert!(stats.retry_in().unwrap() > first);
+        stats.connected();
+        assert!(stats.retry_in().is_none());
+    }
+
+    #[test]
+    fn slowest_queries_are_kept_in_order() {
+        let record = |duration: u64| {
+            let result = Ok(json!({"status": "ok", "affected": 0, "rows": [[1], [2]]}));
+            QueryRecord::new(
+                "SELECT 1",
+                "[1, 2]",
+                Duration::from_millis(duration),
+                &result,
+            )
+        };
+        let mut slowest = Vec::new();
+        for duration in 0..SLOWEST_QUERIES_KEPT as u64 * 2 {
+            keep_slowest(&mut slowest, record(duration * 7 % 200));
+        }
+        assert_eq!(slowest.len(), SLOWEST_QUERIES_KEPT);
+        assert!(slowest.windows(2).all(|w| w[0].duration >= w[1].duration));
+        assert_eq!(slowest[0].duration, 0.199);
+        #[cfg(feature = "log")]
+        assert_eq!(
+            slowest[0].to_log_line(),
+            "0.199s, 2 params, 2 rows: SELECT 1"
+        );
     }
 }
@@ -0,0 +1,616 @@
+//! PostgreSQL backend, picked with `"backend": "postgres"` in the connect
+//! options. Takes the same connection and thread options as MySQL, and the
+//! same params and result shape.
+use super::{
+    bytes_to_json, json_to_mysql, parse_seconds, plain_column_to_json, temporal_to_string,
+    thread_limits, unique_keys, ConnectOptions, Killer, QueryControl, QueryOptions, SslOptions,
+    SslVerify,
+};
+use crate::error::{Error, Result};
+use bytes::BytesMut;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc};
+use native_tls::{Certificate, Identity, TlsConnector};
+use postgres::{
+    config::SslMode,
+    fallible_iterator::FallibleIterator,
+    types::{to_sql_checked, Date, Format, FromSql, IsNull, Timestamp, ToSql, Type},
+    Client, Column, Config, NoTls, Row,
+};
+use postgres_native_tls::MakeTlsConnector;
+use serde_json::json;
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const DEFAULT_PORT: u16 = 5432;
+// How long a query without a timeout of its own waits for a free client.
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(super) struct Database {
+    config: Config,
+    tls: Option<MakeTlsConnector>,
+    max_threads: usize,
+    idle: Mutex<Idle>,
+    returned: Condvar,
+}
+
+struct Idle {
+    clients: Vec<Client>,
+    // Including those in use.
+    open: usize,
+}
+
+// A client borrowed from the pool, put back when dropped.
+struct PooledClient<'a> {
+    database: &'a Database,
+    client: Option<Client>,
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(mut client) = self.client.take() {
+            // A transaction left open, or failed, mustn't carry over to the
+            // next caller. A client which can't even roll back is given up.
+            let reusable = !client.is_closed() && client.batch_execute("ROLLBACK").is_ok();
+            let mut idle = self.database.idle.lock().unwrap();
+            if reusable {
+                idle.clients.push(client);
+            } else {
+                idle.open -= 1;
+            }
+            self.database.returned.notify_one();
+        }
+    }
+}
+
+impl std::ops::Deref for PooledClient<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl std::ops::DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Database {
+    pub(super) fn open(options: &ConnectOptions) -> Result<Database> {
+        let mut config = Config::new();
+        // A host starting with `/` is a socket directory.
+        config
+            .host(match &options.socket_path {
+                Some(path) => path,
+                None => options.host.as_deref().unwrap_or("localhost"),
+            })
+            .port(options.port.unwrap_or(DEFAULT_PORT));
+        if let Some(timeout) = parse_seconds("connect_timeout", options.connect_timeout)? {
+            config.connect_timeout(timeout);
+        }
+        let tls = options.ssl.as_ref().map(tls_connector).transpose()?;
+        if tls.is_some() {
+            config.ssl_mode(SslMode::Require);
+        }
+        if let Some(user) = &options.user {
+            config.user(user);
+        }
+        if let Some(pass) = &options.pass {
+            config.password(pass);
+        }
+        if let Some(db_name) = &options.db_name {
+            config.dbname(db_name);
+        }
+        let (min_threads, max_threads) = thread_limits(options);
+        // Connecting straight away means bad options fail here, as with MySQL.
+        let clients = (0..min_threads.clamp(1, max_threads.max(1)))
+            .map(|_| connect(&config, &tls))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Database {
+            config,
+            tls,
+            max_threads,
+            idle: Mutex::new(Idle {
+                open: clients.len(),
+                clients,
+            }),
+            returned: Condvar::new(),
+        })
+    }
+
+    // Waits for a client if `max_threads` are already in use, for up to the
+    // query's timeout.
+    fn get_client(&self, timeout: Option<Duration>) -> Result<PooledClient<'_>> {
+        let timeout = timeout.unwrap_or(CHECKOUT_TIMEOUT);
+        let deadline = Instant::now() + timeout;
+        let mut idle = self.idle.lock().unwrap();
+        loop {
+            if let Some(client) = idle.clients.pop() {
+                if client.is_closed() {
+                    idle.open -= 1;
+                    continue;
+                }
+                return Ok(PooledClient {
+                    database: self,
+                    client: Some(client),
+                });
+            }
+            if idle.open < self.max_threads {
+                idle.open += 1;
+                drop(idle);
+                return match connect(&self.config, &self.tls) {
+                    Ok(client) => Ok(PooledClient {
+                        database: self,
+                        client: Some(client),
+                    }),
+                    Err(e) => {
+                        self.idle.lock().unwrap().open -= 1;
+                        self.returned.notify_one();
+                        Err(e)
+                    }
+                };
+            }
+            let wait = deadline.saturating_duration_since(Instant::now());
+            if wait.is_zero() {
+                return Err(Error::SqlTimedOut(timeout.as_secs_f32()));
+            }
+            idle = self.returned.wait_timeout(idle, wait).unwrap().0;
+        }
+    }
+
+    pub(super) fn query(
+        &self,
+        query: &str,
+        params: &str,
+        options: &QueryOptions,
+        control: &QueryControl,
+        timeout: Option<Duration>,
+    ) -> Result<serde_json::Value> {
+        let mut client = self.get_client(timeout)?;
+        let killer = Killer::Postgres(client.cancel_token(), self.tls.clone());
+        control.run(killer, timeout, || {
+            run_query(&mut client, query, params, options)
+        })
+    }
+
+    pub(super) fn ping(&self) -> Result<serde_json::Value> {
+        {
+            let idle = self.idle.lock().unwrap();
+            if idle.clients.is_empty() && idle.open >= self.max_threads {
+                return Ok(json!({"status": "busy"}));
+            }
+        }
+        let started = Instant::now();
+        let result = self
+            .get_client(None)
+            .and_then(|mut client| Ok(client.simple_query("SELECT 1")?));
+        if let Err(e) = result {
+            return Ok(json!({"status": "unreachable", "data": e.to_string()}));
+        }
+        Ok(json!({
+            "status": "online",
+            "latency": started.elapsed().as_secs_f64() * 1000.0,
+        }))
+    }
+}
+
+fn connect(config: &Config, tls: &Option<MakeTlsConnector>) -> Result<Client> {
+    Ok(match tls {
+        Some(tls) => config.connect(tls.clone())?,
+        None => config.connect(NoTls)?,
+    })
+}
+
+fn tls_connector(ssl: &SslOptions) -> Result<MakeTlsConnector> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ca_file) = &ssl.ca_file {
+        let ca = std::fs::read(ca_file)?;
+        builder.add_root_certificate(
+            Certificate::from_pem(&ca).or_else(|_| Certificate::from_der(&ca))?,
+        );
+    }
+    if let Some(client_cert) = &ssl.client_cert {
+        let archive = std::fs::read(client_cert)?;
+        let pass = ssl.client_cert_pass.as_deref().unwrap_or_default();
+        builder.identity(Identity::from_pkcs12(&archive, pass)?);
+    }
+    match ssl.verify {
+        SslVerify::Full => {}
+        SslVerify::Ca => {
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        SslVerify::Disabled => {
+            builder.danger_accept_invalid_certs(true);
+        }
+    }
+    Ok(MakeTlsConnector::new(builder.build()?))
+}
+
+fn run_query(
+    client: &mut Client,
+    query: &str,
+    params: &str,
+    options: &QueryOptions,
+) -> Result<serde_json::Value> {
+    let (query, params) = bind_params(query, params)?;
+    let stmt = client.prepare(&query)?;
+    if params.len() != stmt.params().len() {
+        return Err(Error::SqlParam(format!(
+            "the query takes {} but was given {}",
+            stmt.params().len(),
+            params.len()
+        )));
+    }
+    let params: Vec<TextParam> = params
+        .iter()
+        .zip(stmt.params())
+        .map(|(value, ty)| TextParam::new(value, ty))
+        .collect();
+    let columns: Vec<_> = stmt.columns().iter().map(column_to_json).collect();
+    let keys = options
+        .rows_as_objects
+        .then(|| unique_keys(stmt.columns().iter().map(|col| (col.name(), ""))));
+
+    let mut rows: Vec<serde_json::Value> = Vec::new();
+    let mut results = client.query_raw(&stmt, params.iter())?;
+    while let Some(row) = results.next()? {
+        let values = stmt
+            .columns()
+            .iter()
+            .enumerate()
+            .map(|(i, col)| postgres_to_json(&row, i, col.type_()))
+            .collect::<Result<Vec<_>>>()?;
+        rows.push(match &keys {
+            Some(keys) => serde_json::Value::Object(keys.iter().cloned().zip(values).collect()),
+            None => serde_json::Value::Array(values),
+        });
+    }
+
+    Ok(json! {{
+        "status": "ok",
+        "affected": results.rows_affected().unwrap_or(0),
+        // There is no such thing; `RETURNING id` does the job instead.
+        "last_insert_id": null,
+        "columns": columns,
+        "rows": rows,
+    }})
+}
+
+// Postgres only has `$1` placeholders, which a list of params fills as it
+// is. With an object, `:name` placeholders are rewritten into them.
+fn bind_params<'a>(query: &'a str, params: &str) -> Result<(Cow<'a, str>, Vec<mysql::Value>)> {
+    match serde_json::from_str(params) {
+        Ok(serde_json::Value::Array(values)) => Ok((
+            Cow::Borrowed(query),
+            values
+                .into_iter()
+                .map(json_to_mysql)
+                .collect::<Result<_>>()?,
+        )),
+        Ok(serde_json::Value::Object(values)) if !values.is_empty() => {
+            let (query, names) = number_placeholders(query);
+            let values = names
+                .iter()
+                .map(|name| match values.get(name) {
+                    Some(value) => json_to_mysql(value.clone()),
+                    None => Err(Error::SqlParam(format!("missing named parameter {}", name))),
+                })
+                .collect::<Result<_>>()?;
+            Ok((Cow::Owned(query), values))
+        }
+        _ => Ok((Cow::Borrowed(query), Vec::new())),
+    }
+}
+
+// Replaces each `:name` with `$n`, returning the names in order. Skips over
+// strings, quoted identifiers, comments and `::` casts.
+fn number_placeholders(query: &str) -> (String, Vec<String>) {
+    let bytes = query.as_bytes();
+    let mut rewritten = String::with_capacity(query.len());
+    let mut names: Vec<String> = Vec::new();
+    let mut copied = 0;
+    let mut i = 0;
+    let find =
+        |from: usize, what: &str| query[from..].find(what).map_or(query.len(), |at| from + at);
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1).copied()) {
+            (quote @ (b'\'' | b'"'), _) => {
+                i = (find(i + 1, if quote == b'\'' { "'" } else { "\"" }) + 1).min(bytes.len());
+            }
+            (b'-', Some(b'-')) => i = find(i, "\n"),
+            (b'/', Some(b'*')) => i = (find(i + 2, "*/") + 2).min(bytes.len()),
+            (b'$', _) => {
+                // A dollar quoted string, `$tag$ ... $tag$`, or a placeholder.
+                let tag_end = query[i + 1..]
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .map(|at| i + 1 + at);
+                match tag_end {
+                    Some(end) if bytes[end] == b'$' && !bytes[i + 1].is_ascii_digit() => {
+                        let tag = &query[i..=end];
+                        i = (find(end + 1, tag) + tag.len()).min(bytes.len());
+                    }
+                    _ => i += 1,
+                }
+            }
+            (b':', Some(b':')) => i += 2,
+            (b':', Some(c)) if c.is_ascii_alphabetic() || c == b'_' => {
+                let end = query[i + 1..]
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .map_or(query.len(), |at| i + 1 + at);
+                let name = &query[i + 1..end];
+                let number = match names.iter().position(|n| n == name) {
+                    Some(index) => index + 1,
+                    None => {
+                        names.push(name.to_owned());
+                        names.len()
+                    }
+                };
+                rewritten.push_str(&query[copied..i]);
+                let _ = write!(rewritten, "${}", number);
+                copied = end;
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+    rewritten.push_str(&query[copied..]);
+    (rewritten, names)
+}
+
+// Params are sent as text and parsed by the server as whatever type the query
+// wants, so a string can fill a `timestamp` or a number a `numeric`.
+#[derive(Debug)]
+struct TextParam(Option<String>);
+
+impl TextParam {
+    fn new(value: &mysql::Value, ty: &Type) -> TextParam {
+        TextParam(match value {
+            mysql::Value::NULL => None,
+            mysql::Value::Bytes(b) if *ty == Type::BYTEA => {
+                let mut hex = String::with_capacity(2 + b.len() * 2);
+                hex.push_str("\\x");
+                for byte in b {
+                    let _ = write!(hex, "{:02x}", byte);
+                }
+                Some(hex)
+            }
+            mysql::Value::Bytes(b) => Some(String::from_utf8_lossy(b).into_owned()),
+            mysql::Value::Int(i) => Some(i.to_string()),
+            mysql::Value::UInt(u) => Some(u.to_string()),
+            mysql::Value::Float(f) => Some(f.to_string()),
+            mysql::Value::Double(f) => Some(f.to_string()),
+            temporal => temporal_to_string(temporal),
+        })
+    }
+}
+
+impl ToSql for TextParam {
+    fn to_sql(
+        &self,
+        _: &Type,
+        out: &mut BytesMut,
+    ) -> std::result::Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        match &self.0 {
+            Some(text) => {
+                out.extend_from_slice(text.as_bytes());
+                Ok(IsNull::No)
+            }
+            None => Ok(IsNull::Yes),
+        }
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    fn encode_format(&self, _: &Type) -> Format {
+        Format::Text
+    }
+
+    to_sql_checked!();
+}
+
+// A value as the server sent it, for types `postgres` has no decoder for.
+struct Raw<'a>(&'a [u8]);
+
+impl<'a> FromSql<'a> for Raw<'a> {
+    fn from_sql(
+        _: &Type,
+        raw: &'a [u8],
+    ) -> std::result::Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(Raw(raw))
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+}
+
+fn postgres_to_json(row: &Row, i: usize, ty: &Type) -> Result<serde_json::Value> {
+    Ok(match *ty {
+        Type::BOOL => json!(row.try_get::<_, Option<bool>>(i)?),
+        Type::INT2 => json!(row.try_get::<_, Option<i16>>(i)?),
+        Type::INT4 => json!(row.try_get::<_, Option<i32>>(i)?),
+        Type::INT8 => json!(row.try_get::<_, Option<i64>>(i)?),
+        Type::OID => json!(row.try_get::<_, Option<u32>>(i)?),
+        // NaN and infinities become null.
+        Type::FLOAT4 => json!(row.try_get::<_, Option<f32>>(i)?),
+        Type::FLOAT8 => json!(row.try_get::<_, Option<f64>>(i)?),
+        Type::BYTEA => json!(row
+            .try_get::<_, Option<Vec<u8>>>(i)?
+            .map(|bytes| bytes_to_json(&bytes))),
+        Type::JSON | Type::JSONB => json!(row.try_get::<_, Option<serde_json::Value>>(i)?),
+        Type::UUID => json!(row
+            .try_get::<_, Option<Uuid>>(i)?
+            .map(|uuid| uuid.to_string())),
+        Type::DATE => json!(row
+            .try_get::<_, Option<Date<NaiveDate>>>(i)?
+            .map(|date| match date {
+                Date::PosInfinity => "infinity".to_owned(),
+                Date::NegInfinity => "-infinity".to_owned(),
+                Date::Value(date) => datetime_to_string(date.and_time(NaiveTime::MIN)),
+            })),
+        // Time zones are dropped, leaving UTC.
+        Type::TIMESTAMP => json!(row
+            .try_get::<_, Option<Timestamp<NaiveDateTime>>>(i)?
+            .map(|timestamp| timestamp_to_string(timestamp, |t| t))),
+        Type::TIMESTAMPTZ => json!(row
+            .try_get::<_, Option<Timestamp<DateTime<Utc>>>>(i)?
+            .map(|timestamp| timestamp_to_string(timestamp, |t| t.naive_utc()))),
+        // Durations in seconds, like MySQL's TIME.
+        Type::TIME => json!(row.try_get::<_, Option<NaiveTime>>(i)?.map(|time| {
+            f64::from(time.num_seconds_from_midnight()) + f64::from(time.nanosecond()) / 1e9
+        })),
+        _ => match row.try_get::<_, Option<Raw>>(i)? {
+            Some(Raw(raw)) => raw_to_json(ty, raw).unwrap_or(serde_json::Value::Null),
+            None => serde_json::Value::Null,
+        },
+    })
+}
+
+// `None` if the server sent something malformed.
+fn raw_to_json(ty: &Type, raw: &[u8]) -> Option<serde_json::Value> {
+    Some(match *ty {
+        Type::NUMERIC => numeric_to_string(raw)?.into(),
+        // Seconds since midnight, as TIME, with the time zone dropped.
+        Type::TIMETZ => micros_to_seconds(i64::from_be_bytes(raw.get(..8)?.try_into().ok()?)),
+        Type::INTERVAL => {
+            let micros = i64::from_be_bytes(raw.get(..8)?.try_into().ok()?);
+            let days = i32::from_be_bytes(raw.get(8..12)?.try_into().ok()?);
+            let months = i32::from_be_bytes(raw.get(12..16)?.try_into().ok()?);
+            let days = i64::from(days) + i64::from(months) * 30;
+            micros_to_seconds(micros + days * 86_400_000_000)
+        }
+        // Text types, enums and anything else sent as text.
+        _ => match std::str::from_utf8(raw) {
+            Ok(text) => text.into(),
+            Err(_) => bytes_to_json(raw),
+        },
+    })
+}
+
+fn timestamp_to_string<T>(
+    timestamp: Timestamp<T>,
+    naive: impl FnOnce(T) -> NaiveDateTime,
+) -> String {
+    match timestamp {
+        Timestamp::PosInfinity => "infinity".to_owned(),
+        Timestamp::NegInfinity => "-infinity".to_owned(),
+        Timestamp::Value(value) => datetime_to_string(naive(value)),
+    }
+}
+
+fn datetime_to_string(datetime: NaiveDateTime) -> String {
+    let text = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
+    match datetime.nanosecond() / 1000 {
+        0 => text,
+        micros => format!("{}.{:06}", text, micros),
+    }
+}
+
+fn micros_to_seconds(micros: i64) -> serde_json::Value {
+    json!(micros as f64 / 1_000_000.0)
+}
+
+// Base 10000 digits, with the weight of the first, a sign, and how many
+// decimal places to show.
+fn numeric_to_string(raw: &[u8]) -> Option<String> {
+    let header = |at: usize| {
+        raw.get(at..at + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+    let count = usize::from(header(0)?);
+    let weight = header(2)? as i16;
+    let sign = header(4)?;
+    let scale = usize::from(header(6)?);
+    let digits = (0..count)
+        .map(|n| header(8 + n * 2))
+        .collect::<Option<Vec<_>>>()?;
+    match sign {
+        0xC000 => return Some("NaN".to_owned()),
+        0xD000 => return Some("Infinity".to_owned()),
+        0xF000 => return Some("-Infinity".to_owned()),
+        _ => {}
+    }
+    // The digit worth 10000^power.
+    let digit = |power: i16| {
+        usize::try_from(weight - power)
+            .ok()
+            .and_then(|n| digits.get(n).copied())
+            .unwrap_or(0)
+    };
+
+    let mut text = String::new();
+    if sign == 0x4000 {
+        text.push('-');
+    }
+    if weight < 0 {
+        text.push('0');
+    } else {
+        let _ = write!(text, "{}", digit(weight));
+        for power in (0..weight).rev() {
+            let _ = write!(text, "{:04}", digit(power));
+        }
+    }
+    if scale > 0 {
+        let mut fraction = String::new();
+        let mut power = -1;
+        while fraction.len() < scale {
+            let _ = write!(fraction, "{:04}", digit(power));
+            power -= 1;
+        }
+        fraction.truncate(scale);
+        text.push('.');
+        text.push_str(&fraction);
+    }
+    Some(text)
+}
+
+fn column_to_json(col: &Column) -> serde_json::Value {
+    plain_column_to_json(col.name(), col.type_().name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_placeholders_are_numbered() {
+        let (query, names) = number_placeholders(
+            "SELECT :a, ':b', \":c\", x::int, $$:d$$, $t$:e$t$, :a_2, $1 -- :f\n/* :g */ + :a",
+        );
+        assert_eq!(
+            query,
+            "SELECT $1, ':b', \":c\", x::int, $$:d$$, $t$:e$t$, $2, $1 -- :f\n/* :g */ + $1"
+        );
+        assert_eq!(names, ["a", "a_2"]);
+    }
+
+    #[test]
+    fn numerics_convert_exactly() {
+        fn numeric(weight: i16, sign: u16, scale: u16, digits: &[u16]) -> Vec<u8> {
+            let mut raw = Vec::new();
+            for value in [digits.len() as u16, weight as u16, sign, scale]
+                .iter()
+                .chain(digits)
+            {
+                raw.extend_from_slice(&value.to_be_bytes());
+            }
+            raw
+        }
+        let cases = [
+            (numeric(1, 0, 3, &[1, 2345, 6780]), "12345.678"),
+            (numeric(-1, 0x4000, 4, &[12]), "-0.0012"),
+            (numeric(2, 0, 0, &[7]), "700000000"),
+            (numeric(0, 0, 2, &[]), "0.00"),
+            (numeric(0, 0xC000, 0, &[]), "NaN"),
+        ];
+        for (raw, text) in cases {
+            assert_eq!(numeric_to_string(&raw).as_deref(), Some(text));
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! SQLite backend, for running the `sql` functions against a database file
+//! instead of a server. Picked with `"backend": "sqlite"` and a `path` in the
+//! connect options.
+//!
+//! A handle has a single connection, so its queries run one at a time. That
+//! also keeps a `:memory:` database around for as long as the handle.
+use super::{
+    bytes_to_json, json_to_mysql, plain_column_to_json, temporal_to_string, unique_keys,
+    ConnectOptions, Killer, QueryControl, QueryOptions,
+};
+use crate::error::{Error, Result};
+use rusqlite::{
+    types::{Value, ValueRef},
+    Column, Connection, Statement,
+};
+use serde_json::{json, Map, Number};
+use std::sync::{Mutex, PoisonError, TryLockError};
+use std::time::{Duration, Instant};
+
+pub(super) struct Database {
+    conn: Mutex<Connection>,
+}
+
+impl Database {
+    pub(super) fn open(options: &ConnectOptions) -> Result<Database> {
+        let path = options
+            .path
+            .as_deref()
+            .ok_or_else(|| Error::SqlParam("path: required for SQLite".to_owned()))?;
+        Ok(Database {
+            conn: Mutex::new(Connection::open(path)?),
+        })
+    }
+
+    pub(super) fn query(
+        &self,
+        query: &str,
+        params: &str,
+        options: &QueryOptions,
+        control: &QueryControl,
+        timeout: Option<Duration>,
+    ) -> Result<serde_json::Value> {
+        // A panic mid query leaves nothing half done that SQLite won't undo.
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let killer = Killer::Sqlite(conn.get_interrupt_handle());
+        control.run(killer, timeout, || run_query(&conn, query, params, options))
+    }
+
+    pub(super) fn ping(&self) -> Result<serde_json::Value> {
+        let started = Instant::now();
+        let conn = match self.conn.try_lock() {
+            Ok(conn) => conn,
+            Err(TryLockError::WouldBlock) => return Ok(json!({"status": "busy"})),
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        };
+        if let Err(e) = conn.query_row("SELECT 1", [], |_| Ok(())) {
+            return Ok(json!({"status": "unreachable", "data": e.to_string()}));
+        }
+        Ok(json!({
+            "status": "online",
+            "latency": started.elapsed().as_secs_f64() * 1000.0,
+        }))
+    }
+}
+
+fn run_query(
+    conn: &Connection,
+    query: &str,
+    params: &str,
+    options: &QueryOptions,
+) -> Result<serde_json::Value> {
+    let mut stmt = conn.prepare(query)?;
+    bind_params(&mut stmt, params)?;
+    let columns: Vec<_> = stmt.columns().iter().map(column_to_json).collect();
+    let keys = options
+        .rows_as_objects
+        .then(|| unique_keys(stmt.column_names().into_iter().map(|name| (name, ""))));
+    let column_count = stmt.column_count();
+    let (changes_before, rowid_before) = counters(conn)?;
+
+    let mut rows: Vec<serde_json::Value> = Vec::new();
+    let mut results = stmt.raw_query();
+    while let Some(row) = results.next()? {
+        let values = (0..column_count)
+            .map(|i| Ok(sqlite_to_json(row.get_ref(i)?)))
+            .collect::<Result<Vec<_>>>()?;
+        rows.push(match &keys {
+            Some(keys) => serde_json::Value::Object(keys.iter().cloned().zip(values).collect()),
+            None => serde_json::Value::Array(values),
+        });
+    }
+
+    let (changes_after, rowid_after) = counters(conn)?;
+    Ok(json! {{
+        "status": "ok",
+        "affected": changes_after - changes_before,
+        "last_insert_id": (rowid_after != rowid_before).then_some(rowid_after),
+        "columns": columns,
+        "rows": rows,
+    }})
+}
+
+// `changes` and the last rowid are left over from whatever last wrote, so
+// statements which don't write are told apart by these not moving. An insert
+// which happens to get the same rowid as the one before it goes unreported.
+fn counters(conn: &Connection) -> Result<(i64, i64)> {
+    let counters = conn.query_row("SELECT total_changes(), last_insert_rowid()", [], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+    Ok(counters)
+}
+
+// Takes the same json as the MySQL backend. Named parameters may be written
+// `:name`, `@name` or `$name` in the query.
+fn bind_params(stmt: &mut Statement, params: &str) -> Result<()> {
+    let expected = stmt.parameter_count();
+    match serde_json::from_str(params) {
+        Ok(serde_json::Value::Array(values)) if !values.is_empty() => {
+            if values.len() != expected {
+                return Err(rusqlite::Error::InvalidParameterCount(values.len(), expected).into());
+            }
+            for (i, value) in values.into_iter().enumerate() {
+                stmt.raw_bind_parameter(i + 1, json_to_sqlite(value)?)?;
+            }
+        }
+        Ok(serde_json::Value::Object(mut values)) if !values.is_empty() => {
+            for i in 1..=expected {
+                let name = match stmt.parameter_name(i) {
+                    Some(name) => name[1..].to_owned(),
+                    None => return Err(Error::SqlParam(format!("?{} has no name", i))),
+                };
+                let value = values
+                    .remove(&name)
+                    .ok_or_else(|| Error::SqlParam(format!("missing named parameter {}", name)))?;
+                stmt.raw_bind_parameter(i, json_to_sqlite(value)?)?;
+            }
+        }
+        // SQLite would quietly bind NULL to each placeholder instead.
+        _ if expected > 0 => {
+            return Err(rusqlite::Error::InvalidParameterCount(0, expected).into());
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Goes through the MySQL conversion, so typed params mean the same thing to
+// both backends.
+fn json_to_sqlite(value: serde_json::Value) -> Result<Value> {
+    let blob = match &value {
+        serde_json::Value::Array(_) => true,
+        serde_json::Value::Object(o) => is_blob(o),
+        _ => false,
+    };
+    Ok(match json_to_mysql(value)? {
+        mysql::Value::NULL => Value::Null,
+        mysql::Value::Bytes(b) if blob => Value::Blob(b),
+        mysql::Value::Bytes(b) => match String::from_utf8(b) {
+            Ok(s) => Value::Text(s),
+            Err(e) => Value::Blob(e.into_bytes()),
+        },
+        mysql::Value::Int(i) => Value::Integer(i),
+        mysql::Value::UInt(u) => i64::try_from(u).map_or(Value::Real(u as f64), Value::Integer),
+        mysql::Value::Float(f) => Value::Real(f.into()),
+        mysql::Value::Double(f) => Value::Real(f),
+        temporal => Value::Text(temporal_to_string(&temporal).unwrap_or_default()),
+    })
+}
+
+fn is_blob(param: &Map<String, serde_json::Value>) -> bool {
+    param.get("type").and_then(|t| t.as_str()) == Some("blob_base64")
+}
+
+fn sqlite_to_json(value: ValueRef) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => Number::from_f64(f).map_or(serde_json::Value::Null, Into::into),
+        ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+        ValueRef::Blob(b) => bytes_to_json(b),
+    }
+}
+
+// SQLite only knows the type a column was declared with, and only for columns
+// read straight from a table; expressions are "unknown".
+fn column_to_json(col: &Column) -> serde_json::Value {
+    let type_name = match col.decl_type() {
+        Some(decl) => decl
+            .split('(')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase(),
+        None => "unknown".to_owned(),
+    };
+    plain_column_to_json(col.name(), &type_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> Database {
+        let options = r#"{"backend": "sqlite", "path": ":memory:"}"#;
+        Database::open(&serde_json::from_str(options).unwrap()).unwrap()
+    }
+
+    fn query(database: &Database, query: &str, params: &str) -> serde_json::Value {
+        let options = QueryOptions::default();
+        database
+            .query(query, params, &options, &QueryControl::default(), None)
+            .unwrap()
+    }
+
+    #[test]
+    fn queries_match_mysql_shape() {
+        let database = memory();
+        query(
+            &database,
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name VARCHAR(32), data BLOB, at DATETIME)",
+            "",
+        );
+        let inserted = query(
+            &database,
+            "INSERT INTO t (name, data, at) VALUES (?, ?, ?)",
+            r#"["one", [1, 2], {"type": "datetime", "value": "2024-01-02 03:04:05"}]"#,
+        );
+        assert_eq!(inserted["affected"], 1);
+        assert_eq!(inserted["last_insert_id"], 1);
+        query(
+            &database,
+            "INSERT INTO t (name) VALUES (:name)",
+            r#"{"name": "two"}"#,
+        );
+
+        let selected = query(&database, "SELECT id, name, data, at, 1.5 FROM t", "");
+        assert_eq!(selected["affected"], 0);
+        assert_eq!(selected["last_insert_id"], serde_json::Value::Null);
+        assert_eq!(selected["columns"][1]["type"], "varchar");
+        assert_eq!(selected["columns"][4]["type"], "unknown");
+        assert_eq!(
+            selected["rows"],
+            json!([
+                [1, "one", [1, 2], "2024-01-02 03:04:05", 1.5],
+                [2, "two", null, null, 1.5],
+            ])
+        );
+    }
+
+    #[test]
+    fn params_must_all_be_given() {
+        let database = memory();
+        let options = QueryOptions::default();
+        let control = QueryControl::default();
+        for (sql, params) in [
+            ("SELECT ?, ?", "[1]"),
+            ("SELECT ?", ""),
+            ("SELECT :a, :b", r#"{"a": 1}"#),
+        ] {
+            assert!(database
+                .query(sql, params, &options, &control, None)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn only_inserts_report_an_id() {
+        let database = memory();
+        query(&database, "CREATE TABLE a (id INTEGER PRIMARY KEY)", "");
+        query(&database, "CREATE TABLE b (id INTEGER PRIMARY KEY)", "");
+        assert_eq!(
+            query(&database, "INSERT INTO a VALUES (NULL)", "")["last_insert_id"],
+            1
+        );
+        let inserted = query(
+            &database,
+            "WITH x(n) AS (SELECT 5) REPLACE INTO b SELECT n FROM x",
+            "",
+        );
+        assert_eq!(inserted["last_insert_id"], 5);
+        let updated = query(&database, "UPDATE a SET id = 2", "");
+        assert_eq!(updated["affected"], 1);
+        assert_eq!(updated["last_insert_id"], serde_json::Value::Null);
+        // Schema changes leave the last statement's count alone.
+        let created = query(&database, "CREATE TABLE c (id INTEGER PRIMARY KEY)", "");
+        assert_eq!(created["affected"], 0);
+        assert_eq!(created["last_insert_id"], serde_json::Value::Null);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::error::Result;
+use crate::error::{envelope, Result};
 
 byond_fn!(fn toml_file_to_json(path) {
     serde_json::to_string(
@@ -14,11 +14,13 @@ byond_fn!(fn toml_file_to_json(path) {
 });
 
 fn toml_file_to_json_impl(path: &str) -> Result<String> {
-    Ok(serde_json::to_string(&toml_dep::from_str::<
-        toml_dep::Value,
-    >(&std::fs::read_to_string(
-        path,
-    )?)?)?)
+    Ok(serde_json::to_string(&toml_file_to_value(path)?)?)
+}
+
+fn toml_file_to_value(path: &str) -> Result<serde_json::Value> {
+    Ok(serde_json::to_value(
+        toml_dep::from_str::<toml_dep::Value>(&std::fs::read_to_string(path)?)?,
+    )?)
 }
 
 byond_fn!(fn toml_encode(value) {
@@ -40,3 +42,12 @@ fn toml_encode_impl(value: &str) -> Result<String> {
         toml_dep::Value,
     >(value)?)?)
 }
+
+// Unlike above, the parsed file is returned as JSON rather than a string of it.
+byond_fn!(fn toml_file_to_json_v2(path) {
+    Some(envelope(toml_file_to_value(path)))
+});
+
+byond_fn!(fn toml_encode_v2(value) {
+    Some(envelope(toml_encode_impl(value)))
+});
@@ -1,4 +1,8 @@
-use crate::{error::Result, http::HTTP_CLIENT, jobs};
+use crate::{
+    error::{envelope, Result},
+    http::HTTP_CLIENT,
+    jobs,
+};
 use reqwest::blocking::RequestBuilder;
 use std::fs;
 use std::io::Write;
@@ -22,11 +26,16 @@ fn construct_unzip(url: &str, unzip_directory: &str) -> UnzipPrep {
 
 byond_fn!(fn unzip_download_async(url, unzip_directory) {
     let unzip = construct_unzip(url, unzip_directory);
-    Some(jobs::start(move ||
+    Some(jobs::start("unzip", move ||
         do_unzip_download(unzip).unwrap_or_else(|e| e.to_string())
     ))
 });
 
+byond_fn!(fn unzip_download_async_v2(url, unzip_directory) {
+    let unzip = construct_unzip(url, unzip_directory);
+    Some(jobs::start("unzip", move || envelope(do_unzip_download(unzip).map(|_| true))))
+});
+
 fn do_unzip_download(prep: UnzipPrep) -> Result<String> {
     let unzip_path = Path::new(&prep.unzip_directory);
     let response = prep.req.send()?;
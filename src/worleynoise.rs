@@ -1,4 +1,4 @@
-use crate::error::Result;
+use crate::error::{envelope, Result};
 use core::panic;
 use rand::prelude::*;
 use rayon::iter::{IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator};
@@ -12,6 +12,10 @@ byond_fn!(fn worley_generate(region_size, threshold, node_per_region_chance, siz
     worley_noise(region_size, threshold, node_per_region_chance, size, node_min, node_max).ok()
 });
 
+byond_fn!(fn worley_generate_v2(region_size, threshold, node_per_region_chance, size, node_min, node_max) {
+    Some(envelope(worley_noise(region_size, threshold, node_per_region_chance, size, node_min, node_max)))
+});
+
 const RANGE: usize = 4;
 
 // This is a quite complex algorithm basically what it does is it creates 2 maps, one filled with cells and the other with 'regions' that map onto these cells.
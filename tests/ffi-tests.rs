@@ -0,0 +1,882 @@
+//! Drives the exported functions through the C ABI, the same way BYOND does,
+//! so they can be tested without a BYOND install.
+use std::{
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+type ByondFn = unsafe extern "C" fn(c_int, *const *const c_char) -> *const c_char;
+
+fn call(f: ByondFn, args: &[&str]) -> String {
+    let args: Vec<CString> = args.iter().map(|arg| CString::new(*arg).unwrap()).collect();
+    let argv: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+    unsafe { CStr::from_ptr(f(argv.len() as c_int, argv.as_ptr())) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[allow(dead_code)]
+fn wait_for_job(check: ByondFn, id: &str) -> String {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        let result = call(check, &[id]);
+        if result != "NO RESULTS YET" {
+            return result;
+        }
+        assert!(Instant::now() < deadline, "job {} never finished", id);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[allow(dead_code)]
+fn scratch_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust-g-ffi-tests-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+// Exported from a private module, so only reachable by symbol.
+extern "C" {
+    fn get_build_info(argc: c_int, argv: *const *const c_char) -> *const c_char;
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn build_info() {
+    let info: serde_json::Value = serde_json::from_str(&call(get_build_info, &[])).unwrap();
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(info["exports"]["get_build_info"], serde_json::json!([]));
+    #[cfg(feature = "log")]
+    assert_eq!(
+        info["exports"]["log_write"],
+        serde_json::json!(["path", "data", "...rest"])
+    );
+    #[cfg(feature = "http")]
+    assert!(info["features"]
+        .as_array()
+        .unwrap()
+        .contains(&serde_json::json!("http")));
+}
+
+#[cfg(feature = "file")]
+#[test]
+fn file() {
+    use rust_g::file::*;
+
+    let path = scratch_path("file.txt");
+    let path = path.to_str().unwrap();
+    assert_eq!(call(file_exists, &[path]), "false");
+    assert_eq!(call(file_write, &["first\r\nsecond\n", path]), "");
+    assert_eq!(call(file_append, &["third\n", path]), "");
+    assert_eq!(call(file_exists, &[path]), "true");
+    assert_eq!(call(file_read, &[path]), "first\nsecond\nthird\n");
+    assert_eq!(call(file_get_line_count, &[path]), "3");
+    assert_eq!(call(file_seek_line, &[path, "1"]), "second");
+    assert_eq!(call(file_seek_line, &[path, "not a number"]), "");
+
+    let envelope =
+        |response: String| -> serde_json::Value { serde_json::from_str(&response).unwrap() };
+    assert_eq!(
+        envelope(call(file_seek_line_v2, &[path, "2"]))["data"],
+        "third"
+    );
+    assert!(envelope(call(file_seek_line_v2, &[path, "9"]))["data"].is_null());
+    let response = envelope(call(file_read_v2, &[&format!("{path}.missing")]));
+    assert_eq!(response["ok"], false);
+    assert_eq!(response["error"]["kind"], "io");
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn log() {
+    use rust_g::log::*;
+
+    let path = scratch_path("log/test.log");
+    let path = path.to_str().unwrap();
+    call(log_write, &[path, "hello\nworld"]);
+    call(log_write, &[path, "raw", "false"]);
+    call(log_close_all, &[]);
+
+    let contents = std::fs::read_to_string(path).unwrap();
+    let mut lines = contents.lines();
+    let first = lines.next().unwrap();
+    assert!(
+        first.starts_with('[') && first.ends_with("] hello"),
+        "{}",
+        first
+    );
+    assert_eq!(lines.next(), Some(" - world"));
+    assert_eq!(lines.next(), Some("raw"));
+}
+
+#[cfg(feature = "dmi")]
+#[test]
+fn dmi() {
+    use rust_g::dmi::*;
+
+    let path = scratch_path("dmi.png");
+    let path = path.to_str().unwrap();
+    // Two by two, one pixel per colour as `#rrggbb`.
+    let pixels = "#ff0000#00ff00#0000ff#ffffff";
+    assert_eq!(call(dmi_create_png, &[path, "2", "2", pixels]), "");
+    assert_eq!(call(dmi_resize_png, &[path, "4", "4", "nearest"]), "");
+    assert_eq!(call(dmi_strip_metadata, &[path]), "");
+
+    let reader = png::Decoder::new(std::fs::File::open(path).unwrap())
+        .read_info()
+        .unwrap();
+    assert_eq!((reader.info().width, reader.info().height), (4, 4));
+
+    assert_ne!(call(dmi_create_png, &[path, "2", "2", "#ff"]), "");
+}
+
+#[cfg(feature = "binary_space_partition")]
+#[test]
+fn bsp_generate() {
+    let rooms = call(
+        rust_g::binary_space_partition::bsp_generate,
+        &["100", "100", "42", "10", "3", "3"],
+    );
+    let rooms: Vec<serde_json::Value> = serde_json::from_str(&rooms).unwrap();
+    assert!(!rooms.is_empty());
+    for room in rooms {
+        assert!(room["x2"].as_u64().unwrap() <= 100);
+        assert!(room["y2"].as_u64().unwrap() <= 100);
+    }
+}
+
+#[cfg(feature = "random_room_placement")]
+#[test]
+fn random_room_generate() {
+    let rooms = call(
+        rust_g::random_room_placement::random_room_generate,
+        &["100", "100", "10", "42"],
+    );
+    let rooms: Vec<serde_json::Value> = serde_json::from_str(&rooms).unwrap();
+    assert!(!rooms.is_empty());
+}
+
+#[cfg(feature = "cellularnoise")]
+#[test]
+fn cnoise_generate() {
+    let grid = call(
+        rust_g::cellularnoise::cnoise_generate,
+        &["45", "5", "5", "4", "20", "30"],
+    );
+    assert_eq!(grid.len(), 20 * 30);
+    assert!(grid.chars().all(|c| c == '0' || c == '1'));
+}
+
+#[cfg(feature = "noise")]
+#[test]
+fn noise() {
+    let value: f64 = call(
+        rust_g::noise_gen::noise_get_at_coordinates,
+        &["7", "1.5", "2.5"],
+    )
+    .parse()
+    .unwrap();
+    assert!((0.0..=1.0).contains(&value));
+    assert_eq!(
+        call(
+            rust_g::noise_gen::noise_get_at_coordinates,
+            &["7", "x", "y"]
+        ),
+        ""
+    );
+}
+
+#[cfg(feature = "worleynoise")]
+#[test]
+fn worley_generate() {
+    let grid = call(
+        rust_g::worleynoise::worley_generate,
+        &["3", "5", "50", "20", "1", "3"],
+    );
+    assert_eq!(grid.len(), 20 * 20);
+    assert!(grid.chars().all(|c| c == '0' || c == '1'));
+}
+
+// Answers each connection with its own request line as the body, and the next
+// of `statuses`, asking for retries to come straight away.
+#[cfg(feature = "http")]
+fn mock_http_server(statuses: &[u16]) -> String {
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let statuses = statuses.to_vec();
+    thread::spawn(move || {
+        for (stream, status) in listener.incoming().zip(statuses) {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap() > 2 {
+                header.clear();
+            }
+            let body = request_line.trim_end();
+            write!(
+                stream,
+                "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nX-Mock: yes\r\nRetry-After: 0\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    });
+    format!("http://{}", addr)
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn http() {
+    use rust_g::http::*;
+
+    let url = mock_http_server(&[200, 200]);
+
+    let response = call(
+        http_request_blocking,
+        &["get", &format!("{}/blocking", url), "", "", ""],
+    );
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["status_code"], 200);
+    assert_eq!(response["headers"]["x-mock"], "yes");
+    assert_eq!(response["body"], "GET /blocking HTTP/1.1");
+
+    let id = call(
+        http_request_async,
+        &["post", &format!("{}/async", url), "body", "{}", ""],
+    );
+    let response = wait_for_job(http_check_request, &id);
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["body"], "POST /async HTTP/1.1");
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn http_retries() {
+    use rust_g::http::*;
+
+    let url = mock_http_server(&[503, 429, 200, 500]);
+    let request = |path: &str, retries: u32| -> serde_json::Value {
+        let options = serde_json::json!({"retries": retries, "retry_backoff": 0.01});
+        let response = call(
+            http_request_blocking,
+            &[
+                "get",
+                &format!("{}{}", url, path),
+                "",
+                "",
+                &options.to_string(),
+            ],
+        );
+        serde_json::from_str(&response).unwrap()
+    };
+    let response = request("/retried", 2);
+    assert_eq!(response["status_code"], 200);
+    assert_eq!(response["body"], "GET /retried HTTP/1.1");
+    // Out of retries, so the error is the response.
+    assert_eq!(request("/failed", 0)["status_code"], 500);
+
+    let url = mock_http_server(&[503, 503, 200]);
+    let post = |options: serde_json::Value| -> serde_json::Value {
+        let response = call(
+            http_request_blocking,
+            &["post", &url, "", "", &options.to_string()],
+        );
+        serde_json::from_str(&response).unwrap()
+    };
+    // The server may have acted on it, so it isn't sent again.
+    let response = post(serde_json::json!({"retries": 2, "retry_backoff": 0.01}));
+    assert_eq!(response["status_code"], 503);
+    let response = post(serde_json::json!({
+        "retries": 2,
+        "retry_backoff": 0.01,
+        "retry_non_idempotent": true,
+    }));
+    assert_eq!(response["status_code"], 200);
+
+    // Accepts connections but never answers.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let started = std::time::Instant::now();
+    let response = call(
+        http_request_blocking_v2,
+        &["get", &url, "", "", r#"{"timeout": 0.2}"#],
+    );
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["ok"], false, "{}", response);
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    drop(listener);
+}
+
+#[cfg(feature = "sql")]
+#[test]
+fn sql_unreachable() {
+    use rust_g::sql::*;
+
+    let response = call(
+        sql_connect_pool,
+        &[r#"{"host": "127.0.0.1", "port": 1, "user": "nobody"}"#],
+    );
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["status"], "err");
+    let response = call(
+        sql_connect_pool,
+        &[r#"{"socket_path": "/nonexistent/mysqld.sock", "connect_timeout": 1, "ssl": {}}"#],
+    );
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["status"], "err");
+
+    let response = call(sql_connected, &["4294967295"]);
+    assert_eq!(response, r#"{"status":"offline"}"#);
+    let response = call(sql_ping_blocking, &["4294967295"]);
+    assert_eq!(response, r#"{"status":"offline"}"#);
+    let response = call(sql_cancel_query, &["no such job"]);
+    assert_eq!(response, r#"{"status":"offline"}"#);
+}
+
+#[cfg(feature = "sql")]
+fn sql_connect(options: &str) -> String {
+    let response = call(rust_g::sql::sql_connect_pool, &[options]);
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["status"], "ok", "{}", response);
+    response["handle"].as_str().unwrap().to_owned()
+}
+
+// Needs a real server: set `RUST_G_TEST_SQL` to the `sql_connect_pool` options
+// for a database the tests may create tables in. Each test gets its own pool.
+#[cfg(feature = "sql")]
+fn mysql_handle() -> Option<String> {
+    match std::env::var("RUST_G_TEST_SQL") {
+        Ok(options) => Some(sql_connect(&options)),
+        Err(_) => {
+            eprintln!("RUST_G_TEST_SQL not set, skipping");
+            None
+        }
+    }
+}
+
+// A pool on a fresh database, for what every backend supports.
+#[cfg(feature = "sql_sqlite")]
+fn sqlite_handle(name: &str) -> String {
+    let path = scratch_path(name);
+    let _ = std::fs::remove_file(&path);
+    sql_connect(&serde_json::json!({"backend": "sqlite", "path": path}).to_string())
+}
+
+#[cfg(feature = "sql")]
+fn sql_ok(response: String) -> serde_json::Value {
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["status"], "ok", "{}", response);
+    response
+}
+
+#[cfg(feature = "sql")]
+fn sql_query(handle: &str, query: &str, params: &str) -> serde_json::Value {
+    sql_ok(call(
+        rust_g::sql::sql_query_blocking,
+        &[handle, query, params],
+    ))
+}
+
+#[cfg(feature = "sql")]
+fn sql_wait(id: &str) -> serde_json::Value {
+    serde_json::from_str(&wait_for_job(rust_g::sql::sql_check_query, id)).unwrap()
+}
+
+// Runs until interrupted, on SQLite.
+#[cfg(feature = "sql_sqlite")]
+const ENDLESS_QUERY: &str =
+    "WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n) SELECT COUNT(*) FROM n";
+
+#[cfg(feature = "sql_sqlite")]
+#[test]
+fn sql_queries() {
+    use rust_g::sql::*;
+
+    let handle = &sqlite_handle("sql_queries.db");
+    let response: serde_json::Value =
+        serde_json::from_str(&call(sql_ping_blocking, &[handle])).unwrap();
+    assert_eq!(response["status"], "online", "{}", response);
+
+    sql_query(
+        handle,
+        "CREATE TABLE test (id INTEGER PRIMARY KEY, name TEXT, score REAL)",
+        "",
+    );
+    let inserted = sql_query(
+        handle,
+        "INSERT INTO test VALUES (?, ?, ?)",
+        r#"[1, "one", 1.5]"#,
+    );
+    assert_eq!(inserted["affected"], 1);
+    let inserted = sql_query(
+        handle,
+        "INSERT INTO test (name, score) VALUES (:name, :score)",
+        r#"{"name": "two", "score": 2.5}"#,
+    );
+    assert_eq!(inserted["last_insert_id"], 2);
+
+    let id = call(
+        sql_query_async,
+        &[
+            handle,
+            "SELECT id, name, score FROM test WHERE id = ?",
+            "[1]",
+        ],
+    );
+    let response = sql_wait(&id);
+    assert_eq!(response["columns"][1]["name"], "name");
+    assert_eq!(response["rows"][0], serde_json::json!([1, "one", 1.5]));
+
+    let id = call(
+        sql_query_async,
+        &[
+            handle,
+            "SELECT id, name FROM test ORDER BY id",
+            "",
+            r#"{"rows_as_objects": true}"#,
+        ],
+    );
+    assert_eq!(
+        sql_wait(&id)["rows"],
+        serde_json::json!([{"id": 1, "name": "one"}, {"id": 2, "name": "two"}])
+    );
+
+    assert_eq!(
+        call(sql_disconnect_pool, &[handle]),
+        r#"{"status":"success"}"#
+    );
+    assert_eq!(call(sql_connected, &[handle]), r#"{"status":"offline"}"#);
+}
+
+#[cfg(feature = "sql_sqlite")]
+#[test]
+fn sql_timeout() {
+    use rust_g::sql::*;
+
+    let handle = &sqlite_handle("sql_timeout.db");
+    let response = call(
+        sql_query_blocking_v2,
+        &[handle, ENDLESS_QUERY, "", r#"{"timeout": 0.2}"#],
+    );
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["error"]["kind"], "sql_timeout", "{}", response);
+
+    let slowest: serde_json::Value =
+        serde_json::from_str(&call(sql_slowest_queries, &["100"])).unwrap();
+    let timed_out = slowest
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|record| record["query"] == ENDLESS_QUERY && record["error"].is_string())
+        .expect("timed out query not recorded");
+    assert!(timed_out["duration"].as_f64().unwrap() >= 0.2);
+}
+
+#[cfg(feature = "sql_sqlite")]
+#[test]
+fn sql_cancel() {
+    use rust_g::sql::*;
+
+    let handle = &sqlite_handle("sql_cancel.db");
+    let id = call(sql_query_async_v2, &[handle, ENDLESS_QUERY, ""]);
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert_eq!(call(sql_cancel_query, &[&id]), r#"{"status":"ok"}"#);
+    let response = sql_wait(&id);
+    assert_eq!(response["error"]["kind"], "sql_cancelled", "{}", response);
+}
+
+#[cfg(feature = "sql_sqlite")]
+#[test]
+fn sql_sqlite_unsupported() {
+    use rust_g::sql::*;
+
+    let handle = &sqlite_handle("sql_sqlite_unsupported.db");
+    let path = scratch_path("sqlite_export.csv");
+    let id = call(
+        sql_export_async,
+        &[handle, "SELECT 1", "", path.to_str().unwrap()],
+    );
+    let response = sql_wait(&id);
+    assert_eq!(response["status"], "err", "{}", response);
+
+    let response: serde_json::Value = serde_json::from_str(&call(sql_begin, &[handle])).unwrap();
+    assert_eq!(response["status"], "err", "{}", response);
+    // Cursors open straight away, and report the failure on the first fetch.
+    let cursor = sql_ok(call(sql_cursor_open, &[handle, "SELECT 1", ""]));
+    let cursor = cursor["cursor"].as_str().unwrap();
+    let response: serde_json::Value =
+        serde_json::from_str(&call(sql_cursor_fetch_blocking, &[cursor, "1"])).unwrap();
+    assert_eq!(response["status"], "err", "{}", response);
+}
+
+#[cfg(feature = "sql")]
+#[test]
+fn sql_mysql_queries() {
+    use rust_g::sql::*;
+
+    let Some(handle) = &mysql_handle() else {
+        return;
+    };
+    sql_query(
+        handle,
+        "CREATE TEMPORARY TABLE rustg_test (id INT PRIMARY KEY, name VARCHAR(32), score DOUBLE)",
+        "",
+    );
+    sql_query(
+        handle,
+        "INSERT INTO rustg_test VALUES (?, ?, ?)",
+        r#"[1, "one", 1.5]"#,
+    );
+    sql_query(
+        handle,
+        "INSERT INTO rustg_test VALUES (:id, :name, :score)",
+        r#"{"id": 2, "name": "two", "score": 2}"#,
+    );
+    let response = sql_query(
+        handle,
+        "SELECT id, name, score FROM rustg_test WHERE id = ?",
+        "[1]",
+    );
+    assert_eq!(response["rows"][0], serde_json::json!([1, "one", 1.5]));
+
+    let response = call(
+        sql_query_blocking,
+        &[
+            handle,
+            // A temporary table can only be named once per query.
+            "SELECT a.id, b.id, a.name FROM rustg_test a JOIN (SELECT 2 AS id) b ON a.id = b.id",
+            "",
+            r#"{"rows_as_objects": true}"#,
+        ],
+    );
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(
+        response["rows"][0],
+        serde_json::json!({"id": 2, "b.id": 2, "name": "two"})
+    );
+
+    let stats: serde_json::Value = serde_json::from_str(&call(sql_pool_stats, &[handle])).unwrap();
+    assert!(stats["active"].is_u64(), "{}", stats);
+    assert!(stats["queries"].as_u64().unwrap() > 0);
+    assert!(stats["open"].is_null(), "{}", stats);
+
+    assert_eq!(
+        call(sql_disconnect_pool, &[handle]),
+        r#"{"status":"success"}"#
+    );
+}
+
+#[cfg(feature = "sql")]
+#[test]
+fn sql_mysql_timeout_and_cancel() {
+    use rust_g::sql::*;
+
+    let Some(handle) = &mysql_handle() else {
+        return;
+    };
+    let response = call(
+        sql_query_blocking_v2,
+        &[handle, "SELECT SLEEP(10)", "", r#"{"timeout": 0.5}"#],
+    );
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["error"]["kind"], "sql_timeout", "{}", response);
+    let id = call(sql_query_async_v2, &[handle, "SELECT SLEEP(10)", ""]);
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert_eq!(call(sql_cancel_query, &[&id]), r#"{"status":"ok"}"#);
+    let response = sql_wait(&id);
+    assert_eq!(response["error"]["kind"], "sql_cancelled", "{}", response);
+}
+
+#[cfg(feature = "sql")]
+#[test]
+fn sql_mysql_export() {
+    use rust_g::sql::*;
+
+    let Some(handle) = &mysql_handle() else {
+        return;
+    };
+    let rows = "SELECT 1 AS id, 'a,b' AS name UNION ALL SELECT 2, NULL";
+    for (name, contents) in [
+        ("export.csv", "id,name\n1,\"a,b\"\n2,\n"),
+        (
+            "export.ndjson",
+            "{\"id\":1,\"name\":\"a,b\"}\n{\"id\":2,\"name\":null}\n",
+        ),
+    ] {
+        let path = scratch_path(name);
+        let id = call(
+            sql_export_async,
+            &[handle, rows, "", path.to_str().unwrap()],
+        );
+        let response = sql_wait(&id);
+        assert_eq!(response["rows"], 2, "{}", response);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+    }
+}
+
+#[cfg(feature = "sql")]
+#[test]
+fn sql_mysql_batch() {
+    use rust_g::sql::*;
+
+    let Some(handle) = &mysql_handle() else {
+        return;
+    };
+    sql_query(
+        handle,
+        "CREATE TEMPORARY TABLE rustg_test_batch (id INT PRIMARY KEY, name VARCHAR(32))",
+        "",
+    );
+    sql_query(handle, "INSERT INTO rustg_test_batch VALUES (1, 'one')", "");
+    let response = call(
+        sql_query_batch_blocking,
+        &[
+            handle,
+            "INSERT INTO rustg_test_batch (id, name) VALUES (?, ?)",
+            r#"[[3, "three"], [1, "duplicate"], [4, "four"]]"#,
+        ],
+    );
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["affected"], 2, "{}", response);
+    assert_eq!(response["errors"][0]["index"], 1);
+    let id = call(
+        sql_query_batch_async,
+        &[
+            handle,
+            "INSERT INTO rustg_test_batch (id, name) VALUES (?, ?)",
+            r#"[[5, "five"], [1, "duplicate"]]"#,
+            r#"{"transaction": true}"#,
+        ],
+    );
+    let response = sql_wait(&id);
+    assert_eq!(response["rolled_back"], true, "{}", response);
+    let rows = sql_query(handle, "SELECT id FROM rustg_test_batch ORDER BY id", "");
+    assert_eq!(rows["rows"], serde_json::json!([[1], [3], [4]]));
+}
+
+#[cfg(feature = "sql")]
+#[test]
+fn sql_mysql_cursor() {
+    use rust_g::sql::*;
+
+    let Some(handle) = &mysql_handle() else {
+        return;
+    };
+    let cursor = sql_ok(call(
+        sql_cursor_open,
+        &[handle, "SELECT 1 AS id UNION ALL SELECT 2", ""],
+    ));
+    let cursor = cursor["cursor"].as_str().unwrap().to_owned();
+    let batch = call(sql_cursor_fetch_blocking, &[&cursor, "1"]);
+    let batch: serde_json::Value = serde_json::from_str(&batch).unwrap();
+    assert_eq!(batch["rows"], serde_json::json!([[1]]));
+    assert_eq!(batch["done"], false);
+    let id = call(sql_cursor_fetch_async, &[&cursor, "10"]);
+    let batch = sql_wait(&id);
+    assert_eq!(batch["rows"], serde_json::json!([[2]]));
+    assert_eq!(batch["done"], true);
+    assert_eq!(
+        call(sql_cursor_close, &[&cursor]),
+        r#"{"status":"offline"}"#,
+        "finished cursors close themselves"
+    );
+
+    let cursor = sql_ok(call(
+        sql_cursor_open,
+        &[handle, "SELECT SLEEP(2) AS slept", ""],
+    ));
+    let cursor = cursor["cursor"].as_str().unwrap().to_owned();
+    let batch = call(sql_cursor_fetch_blocking, &[&cursor, "1"]);
+    assert_eq!(batch, r#"{"status":"busy"}"#);
+    let id = call(sql_cursor_fetch_async, &[&cursor, "10"]);
+    let batch = sql_wait(&id);
+    assert_eq!(batch["rows"], serde_json::json!([[0]]));
+}
+
+#[cfg(feature = "sql")]
+#[test]
+fn sql_mysql_transactions() {
+    use rust_g::sql::*;
+
+    let Some(handle) = &mysql_handle() else {
+        return;
+    };
+    // Temporary tables are per connection, so use a real one for this.
+    sql_query(
+        handle,
+        "CREATE TABLE IF NOT EXISTS rustg_test_txn (id INT PRIMARY KEY) ENGINE=InnoDB",
+        "",
+    );
+    sql_query(handle, "DELETE FROM rustg_test_txn", "");
+    let begin = |args: &[&str]| -> String {
+        sql_ok(call(sql_begin, args))["handle"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    };
+
+    let txn = begin(&[handle]);
+    sql_query(&txn, "INSERT INTO rustg_test_txn VALUES (1)", "");
+    sql_ok(call(sql_rollback, &[&txn]));
+    assert_eq!(
+        call(sql_commit, &[&txn]),
+        r#"{"status":"offline"}"#,
+        "ended transactions are gone"
+    );
+    let txn = begin(&[handle, "30"]);
+    sql_query(&txn, "INSERT INTO rustg_test_txn VALUES (2)", "");
+    sql_ok(call(sql_commit, &[&txn]));
+    let txn = begin(&[handle, "0.2"]);
+    sql_query(&txn, "INSERT INTO rustg_test_txn VALUES (3)", "");
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    assert_eq!(
+        call(sql_commit, &[&txn]),
+        r#"{"status":"offline"}"#,
+        "abandoned transactions are rolled back"
+    );
+    let rows = sql_query(handle, "SELECT id FROM rustg_test_txn", "");
+    assert_eq!(rows["rows"], serde_json::json!([[2]]));
+    sql_query(handle, "DROP TABLE rustg_test_txn", "");
+}
+
+#[cfg(feature = "sql")]
+#[test]
+fn sql_mysql_migrations() {
+    use rust_g::sql::*;
+
+    let Some(handle) = &mysql_handle() else {
+        return;
+    };
+    let migrations = scratch_path("migrations");
+    let _ = std::fs::remove_dir_all(&migrations);
+    std::fs::create_dir_all(&migrations).unwrap();
+    std::fs::write(
+        migrations.join("1_create.sql"),
+        "CREATE TABLE rustg_test_migrated (id INT); INSERT INTO rustg_test_migrated VALUES (1);",
+    )
+    .unwrap();
+    let migrations = migrations.to_str().unwrap();
+    let response = sql_ok(call(sql_migrate_blocking, &[handle, migrations]));
+    assert_eq!(response["applied"][0]["version"], 1);
+    let response = sql_ok(call(sql_migrate_blocking, &[handle, migrations]));
+    assert_eq!(response["applied"], serde_json::json!([]));
+    assert_eq!(response["version"], 1);
+
+    // A failure after the first statement still fails the migration.
+    let broken = scratch_path("migrations/2_broken.sql");
+    std::fs::write(
+        &broken,
+        "INSERT INTO rustg_test_migrated VALUES (2); INSERT INTO rustg_test_missing VALUES (1);",
+    )
+    .unwrap();
+    let response: serde_json::Value =
+        serde_json::from_str(&call(sql_migrate_blocking, &[handle, migrations])).unwrap();
+    assert_eq!(response["status"], "err", "{}", response);
+    assert_eq!(response["applied"], serde_json::json!([]));
+    let rows = sql_query(handle, "SELECT version FROM rustg_schema_migrations", "");
+    assert_eq!(rows["rows"], serde_json::json!([[1]]));
+    std::fs::remove_file(&broken).unwrap();
+
+    std::fs::write(
+        scratch_path("migrations/1_create.sql"),
+        "CREATE TABLE something_else (id INT);",
+    )
+    .unwrap();
+    let response = call(sql_migrate_blocking, &[handle, migrations]);
+    assert!(response.contains("has changed"), "{}", response);
+    sql_query(
+        handle,
+        "DROP TABLE rustg_test_migrated, rustg_schema_migrations",
+        "",
+    );
+}
+
+// Needs a real server: set `RUST_G_TEST_POSTGRES` to the `sql_connect_pool`
+// options (with `"backend": "postgres"`) for a database it may use.
+#[cfg(feature = "sql_postgres")]
+#[test]
+fn sql_postgres() {
+    use rust_g::sql::*;
+
+    let options = match std::env::var("RUST_G_TEST_POSTGRES") {
+        Ok(options) => options,
+        Err(_) => return eprintln!("RUST_G_TEST_POSTGRES not set, skipping"),
+    };
+    let handle = &sql_connect(&options);
+    let query = |query: &str, params: &str| sql_query(handle, query, params);
+    query(
+        "CREATE TEMPORARY TABLE rustg_test (id SERIAL PRIMARY KEY, name TEXT, cost NUMERIC(6, 2), data BYTEA, at TIMESTAMP)",
+        "",
+    );
+    let inserted = query(
+        "INSERT INTO rustg_test (name, cost, data, at) VALUES ($1, $2, $3, $4) RETURNING id",
+        r#"["one", {"type": "decimal", "value": "12.50"}, [1, 2], "2024-01-02 03:04:05.5"]"#,
+    );
+    assert_eq!(inserted["affected"], 1);
+    assert_eq!(inserted["rows"], serde_json::json!([[1]]));
+    query(
+        "INSERT INTO rustg_test (name) VALUES (:name)",
+        r#"{"name": "two"}"#,
+    );
+
+    let response = query(
+        "SELECT id, name, cost, data, at, name = :name AS matched FROM rustg_test WHERE id::int = :id",
+        r#"{"id": 1, "name": "one"}"#,
+    );
+    assert_eq!(response["columns"][2]["type"], "numeric");
+    assert_eq!(
+        response["rows"][0],
+        serde_json::json!([
+            1,
+            "one",
+            "12.50",
+            [1, 2],
+            "2024-01-02 03:04:05.500000",
+            true
+        ])
+    );
+
+    let response = query(
+        "SELECT 'infinity'::date, '{\"a\": 1}'::jsonb, 'a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11'::uuid, '01:02:03.5'::time",
+        "",
+    );
+    assert_eq!(
+        response["rows"][0],
+        serde_json::json!([
+            "infinity",
+            {"a": 1},
+            "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11",
+            3723.5
+        ])
+    );
+
+    let response = call(
+        sql_query_blocking_v2,
+        &[handle, "SELECT pg_sleep(10)", "", r#"{"timeout": 0.5}"#],
+    );
+    let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+    assert_eq!(response["error"]["kind"], "sql_timeout", "{}", response);
+
+    // Clients go back to the pool with no transaction left over, so one
+    // which failed doesn't fail everything after it.
+    query("BEGIN", "");
+    let response: serde_json::Value =
+        serde_json::from_str(&call(sql_query_blocking, &[handle, "SELECT 1 / 0", ""])).unwrap();
+    assert_eq!(response["status"], "err", "{}", response);
+    let response = query("SELECT COUNT(*)::int FROM rustg_test", "");
+    assert_eq!(response["rows"], serde_json::json!([[2]]));
+
+    assert_eq!(
+        call(sql_disconnect_pool, &[handle]),
+        r#"{"status":"success"}"#
+    );
+}